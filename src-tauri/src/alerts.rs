@@ -0,0 +1,310 @@
+use crate::audio::{self, AudioBuffer, Bus};
+use crate::chat::{ChatMessage, MessageKind};
+use crate::control::PLAYBACK;
+use crate::dsp;
+use crate::queue::QueueItem;
+use lazy_static::lazy_static;
+use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+/// File types rodio can decode
+const SOUND_EXTENSIONS: [&str; 4] = ["wav", "ogg", "mp3", "flac"];
+
+/// A file from the sound library and how loud to play it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AlertSound {
+    /// Path relative to the sound library directory
+    pub file: String,
+    pub volume: f32,
+}
+
+impl Default for AlertSound {
+    fn default() -> Self {
+        AlertSound {
+            file: String::new(),
+            volume: 1.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertEvent {
+    Raid,
+    Subscription,
+    Cheer,
+    /// Not played when the reward has its own soundboard clip
+    Redemption,
+    /// A chatter's first ever message in the channel
+    FirstMessage,
+}
+
+/// Plays the sound when a message is read out that contains the keyword as whole words
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeywordAlert {
+    pub keyword: String,
+    pub sound: AlertSound,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AlertsConfig {
+    pub enabled: bool,
+    /// Played right before every TTS message
+    pub message_chime: Option<AlertSound>,
+    /// Played as soon as the event arrives
+    pub events: HashMap<AlertEvent, AlertSound>,
+    /// Checked in order, the first match plays along with its message
+    pub keywords: Vec<KeywordAlert>,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig {
+            enabled: true,
+            message_chime: None,
+            events: HashMap::new(),
+            keywords: Vec::new(),
+        }
+    }
+}
+
+impl AlertsConfig {
+    /// The sound for the event a chat message came from
+    fn event_sound(&self, message: &ChatMessage) -> Option<&AlertSound> {
+        let event = match message.kind {
+            MessageKind::Raid => AlertEvent::Raid,
+            MessageKind::Subscription => AlertEvent::Subscription,
+            MessageKind::Cheer => AlertEvent::Cheer,
+            MessageKind::Redemption => AlertEvent::Redemption,
+            _ if message.first_message => AlertEvent::FirstMessage,
+            _ => return None,
+        };
+        self.events.get(&event)
+    }
+
+    /// The sound of the first keyword found in the text
+    fn keyword_sound(&self, text: &str) -> Option<&AlertSound> {
+        let text = words(text);
+        self.keywords
+            .iter()
+            .find(|alert| {
+                let keyword = words(&alert.keyword);
+                !keyword.is_empty() && text.windows(keyword.len()).any(|window| window == keyword)
+            })
+            .map(|alert| &alert.sound)
+    }
+}
+
+/// Lowercase words with the punctuation around them removed
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+lazy_static! {
+    static ref ALERTS: Mutex<AlertsConfig> = Mutex::new(AlertsConfig::default());
+    static ref LIBRARY_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    /// Decoded sounds by file name, so alerts don't hit the disk every time
+    static ref SOUND_CACHE: Mutex<HashMap<String, AudioBuffer>> = Mutex::new(HashMap::new());
+}
+
+pub fn set_alerts_config(config: AlertsConfig) {
+    *ALERTS.lock().unwrap() = config;
+    // the files may have been replaced since they were cached
+    SOUND_CACHE.lock().unwrap().clear();
+}
+
+/// Sets the directory sounds are loaded from, creating it if needed
+pub fn set_library_dir(dir: PathBuf) {
+    if let Err(e) = fs::create_dir_all(&dir) {
+        println!("Error creating sound library {}: {}", dir.display(), e);
+    }
+    println!("Sound library: {}", dir.display());
+    *LIBRARY_DIR.lock().unwrap() = Some(dir);
+    SOUND_CACHE.lock().unwrap().clear();
+}
+
+pub fn library_dir() -> Result<PathBuf, String> {
+    LIBRARY_DIR
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| "Sound library is not set up".to_string())
+}
+
+/// Every playable file in the sound library, relative to it
+pub fn list_sounds() -> Result<Vec<String>, String> {
+    let dir = library_dir()?;
+    let entries = fs::read_dir(&dir)
+        .map_err(|e| format!("Failed to read sound library {}: {}", dir.display(), e))?;
+    let mut sounds: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| {
+                    SOUND_EXTENSIONS.contains(&extension.to_lowercase().as_str())
+                })
+        })
+        .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+        .collect();
+    sounds.sort();
+    Ok(sounds)
+}
+
+fn decode(path: &Path) -> Result<AudioBuffer, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let decoder = Decoder::new(BufReader::new(file))
+        .map_err(|e| format!("Failed to decode {}: {}", path.display(), e))?;
+    let sample_rate = decoder.sample_rate();
+    let channels = decoder.channels();
+    let samples: Vec<f32> = decoder.convert_samples().collect();
+    Ok(AudioBuffer::new(samples, sample_rate, channels))
+}
+
+/// Loads a sound from the library, files outside of it are refused
+pub fn load_sound(file: &str) -> Result<AudioBuffer, String> {
+    if let Some(sound) = SOUND_CACHE.lock().unwrap().get(file) {
+        return Ok(sound.clone());
+    }
+    let relative = Path::new(file);
+    if file.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(format!("Invalid sound file {}", file));
+    }
+    let sound = decode(&library_dir()?.join(relative))?;
+    SOUND_CACHE
+        .lock()
+        .unwrap()
+        .insert(file.to_string(), sound.clone());
+    Ok(sound)
+}
+
+/// Loads a sound from the library at its configured volume
+pub fn load_with_volume(sound: &AlertSound) -> Result<AudioBuffer, String> {
+    let mut audio = load_sound(&sound.file)?;
+    dsp::apply_gain(&mut audio, sound.volume.max(0.0));
+    Ok(audio)
+}
+
+/// Plays a sound over anything else that is playing
+pub fn play(sound: &AlertSound) -> Result<(), String> {
+    let audio = load_with_volume(sound)?;
+    audio::output()?.play_on(Bus::Alerts, &audio);
+    Ok(())
+}
+
+/// Loops a file from the sound library as background music, None stops it
+pub fn set_music(file: Option<&str>) -> Result<(), String> {
+    let music = file.map(load_sound).transpose()?;
+    audio::set_music(music);
+    Ok(())
+}
+
+/// Plays the event alert for a message that was accepted for reading, if it has one.
+/// The sound is loaded on a blocking thread, the first play of a file decodes all of it.
+pub fn on_message(message: &ChatMessage) {
+    let sound = {
+        let config = ALERTS.lock().unwrap();
+        if !config.enabled {
+            return;
+        }
+        match config.event_sound(message) {
+            Some(sound) => sound.clone(),
+            None => return,
+        }
+    };
+    if PLAYBACK.is_muted() {
+        return;
+    }
+    println!("Playing alert {} for {}", sound.file, message.username);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = play(&sound) {
+            println!("Error playing alert: {}", e);
+        }
+    });
+}
+
+/// Plays the keyword alert for a chat message as it starts being read, if it has one
+pub fn on_read(item: &QueueItem) {
+    if matches!(
+        item.kind,
+        MessageKind::System | MessageKind::Sound | MessageKind::Manual
+    ) {
+        return;
+    }
+    let sound = {
+        let config = ALERTS.lock().unwrap();
+        if !config.enabled {
+            return;
+        }
+        match config.keyword_sound(&item.text) {
+            Some(sound) => sound.clone(),
+            None => return,
+        }
+    };
+    println!("Playing keyword alert {} for {}", sound.file, item.user);
+    if let Err(e) = play(&sound) {
+        println!("Error playing alert: {}", e);
+    }
+}
+
+/// The chime to play before a TTS message, if one is set
+pub fn message_chime() -> Option<AudioBuffer> {
+    let chime = {
+        let config = ALERTS.lock().unwrap();
+        if !config.enabled {
+            return None;
+        }
+        config.message_chime.clone()?
+    };
+    match load_with_volume(&chime) {
+        Ok(audio) => Some(audio),
+        Err(e) => {
+            println!("Error loading message chime: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyword(keyword: &str) -> KeywordAlert {
+        KeywordAlert {
+            keyword: keyword.to_string(),
+            sound: AlertSound {
+                file: format!("{}.wav", keyword),
+                volume: 1.0,
+            },
+        }
+    }
+
+    #[test]
+    fn matches_whole_words_only() {
+        let config = AlertsConfig {
+            keywords: vec![keyword("hi"), keyword("good morning")],
+            ..AlertsConfig::default()
+        };
+        let file = |text| config.keyword_sound(text).map(|sound| sound.file.as_str());
+        assert_eq!(file("this is it"), None);
+        assert_eq!(file("Hi!"), Some("hi.wav"));
+        assert_eq!(file("well, GOOD morning chat"), Some("good morning.wav"));
+        assert_eq!(file("good mornings"), None);
+        assert_eq!(file("good, morning"), Some("good morning.wav"));
+    }
+}
@@ -0,0 +1,812 @@
+use crate::dsp;
+use crate::recorder;
+use lazy_static::lazy_static;
+use rodio::buffer::SamplesBuffer;
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::source::{Source, Zero};
+use rodio::{OutputStream, OutputStreamHandle, Sink};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter};
+
+/// Event emitted whenever playback moves to a different output device or loses its device
+pub const AUDIO_DEVICE_EVENT: &str = "audio-device-changed";
+
+/// How often the monitor checks that the output device is still connected
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Wait before trying to open devices again after an open failed, doubled on every failure
+const RETRY_MIN: Duration = Duration::from_secs(2);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// How often the ducking level is moved towards its target
+const DUCK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A channel of the mixer. Every bus has its own sink on each device,
+/// so sounds on different buses play over each other.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Bus {
+    Tts,
+    Alerts,
+    Soundboard,
+    /// The background music loop
+    Music,
+}
+
+const BUSES: [Bus; 4] = [Bus::Tts, Bus::Alerts, Bus::Soundboard, Bus::Music];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct BusConfig {
+    pub volume: f32,
+    pub muted: bool,
+    /// Turned down while TTS is speaking, has no effect on the TTS bus
+    pub ducked: bool,
+}
+
+impl Default for BusConfig {
+    fn default() -> Self {
+        BusConfig {
+            volume: 1.0,
+            muted: false,
+            ducked: true,
+        }
+    }
+}
+
+impl BusConfig {
+    fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume.max(0.0)
+        }
+    }
+}
+
+/// How the other buses are turned down under speech
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DuckingConfig {
+    pub enabled: bool,
+    /// Volume change of ducked buses while TTS is speaking, in dB
+    pub level_db: f32,
+    /// Time to duck once speech starts
+    pub attack_ms: u64,
+    /// Time to come back up once speech ends
+    pub release_ms: u64,
+}
+
+impl Default for DuckingConfig {
+    fn default() -> Self {
+        DuckingConfig {
+            enabled: true,
+            level_db: -12.0,
+            attack_ms: 100,
+            release_ms: 600,
+        }
+    }
+}
+
+/// Settings for the shared audio output
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// Silence added after every message, 0 plays messages back to back
+    pub message_gap_ms: u64,
+    /// Scales the volume of every output device
+    pub master_volume: f32,
+    /// Every device plays the same audio, an empty list plays on the system default
+    pub outputs: Vec<OutputConfig>,
+    /// Bus settings, a bus that isn't listed plays at full volume
+    pub buses: HashMap<Bus, BusConfig>,
+    pub ducking: DuckingConfig,
+    /// File from the sound library looped on the music bus
+    pub music: Option<String>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            message_gap_ms: 0,
+            master_volume: 1.0,
+            outputs: vec![OutputConfig::default()],
+            buses: HashMap::new(),
+            ducking: DuckingConfig::default(),
+            music: None,
+        }
+    }
+}
+
+impl AudioConfig {
+    fn bus(&self, bus: Bus) -> BusConfig {
+        self.buses.get(&bus).cloned().unwrap_or_default()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Name of the output device, the system default when unset or unavailable
+    pub device: Option<String>,
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            device: None,
+            volume: 1.0,
+            muted: false,
+        }
+    }
+}
+
+impl OutputConfig {
+    fn effective_volume(&self, master_volume: f32) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume.max(0.0) * master_volume.max(0.0)
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AudioDeviceChange {
+    /// The devices now in use, empty if none could be opened
+    pub devices: Vec<String>,
+    pub message: String,
+}
+
+/// Interleaved samples along with the format they were produced in.
+/// rodio converts them to the device's sample rate and channel count when they are played.
+#[derive(Debug, Clone)]
+pub struct AudioBuffer {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl AudioBuffer {
+    pub fn new(samples: Vec<f32>, sample_rate: u32, channels: u16) -> AudioBuffer {
+        AudioBuffer {
+            samples,
+            sample_rate,
+            channels,
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        let frames = self.samples.len() / self.channels.max(1) as usize;
+        Duration::from_secs_f64(frames as f64 / self.sample_rate.max(1) as f64)
+    }
+
+    /// Appends the samples of a buffer in the same format
+    pub fn extend(&mut self, other: &AudioBuffer) {
+        self.samples.extend_from_slice(&other.samples);
+    }
+
+    pub fn to_source(&self) -> SamplesBuffer<f32> {
+        SamplesBuffer::new(self.channels, self.sample_rate, self.samples.clone())
+    }
+}
+
+/// One open output device. rodio's OutputStream can't be moved between threads,
+/// so a thread per device owns the stream and keeps it open until this is dropped.
+struct DeviceOutput {
+    device_name: String,
+    /// Opened in place of a configured device that isn't connected
+    fallback: bool,
+    /// Device volume including the master volume, before any fade
+    volume: Mutex<f32>,
+    /// One sink per bus, rodio mixes them on the device
+    sinks: HashMap<Bus, Sink>,
+    _handle: OutputStreamHandle,
+    _close: mpsc::Sender<()>,
+}
+
+impl DeviceOutput {
+    /// Opens the named device, or the default device if it isn't connected
+    fn open(config: &OutputConfig) -> Result<DeviceOutput, String> {
+        let host = rodio::cpal::default_host();
+        let wanted_device = config.device.as_ref().and_then(|name| {
+            host.output_devices()
+                .ok()?
+                .find(|device| device.name().is_ok_and(|device_name| device_name == *name))
+        });
+        let device = match wanted_device {
+            Some(device) => device,
+            None => host
+                .default_output_device()
+                .ok_or_else(|| "No audio output device available".to_string())?,
+        };
+        let device_name = device.name().unwrap_or_else(|_| "Unknown".to_string());
+        let fallback = config
+            .device
+            .as_ref()
+            .is_some_and(|wanted| *wanted != device_name);
+
+        let (handle_tx, handle_rx) = mpsc::channel();
+        let (close_tx, close_rx) = mpsc::channel::<()>();
+        thread::spawn(move || match OutputStream::try_from_device(&device) {
+            Ok((_stream, handle)) => {
+                let _ = handle_tx.send(Ok(handle));
+                let _ = close_rx.recv();
+                println!("Audio output closed");
+            }
+            Err(e) => {
+                let _ = handle_tx.send(Err(format!("Failed to open audio output: {}", e)));
+            }
+        });
+
+        let handle = handle_rx
+            .recv()
+            .map_err(|e| format!("Audio output thread stopped: {}", e))??;
+        let mut sinks = HashMap::new();
+        for bus in BUSES {
+            let sink = Sink::try_new(&handle)
+                .map_err(|e| format!("Failed to create audio sink: {}", e))?;
+            sinks.insert(bus, sink);
+        }
+        println!("Audio output opened on {}", device_name);
+
+        Ok(DeviceOutput {
+            device_name,
+            fallback,
+            volume: Mutex::new(1.0),
+            sinks,
+            _handle: handle,
+            _close: close_tx,
+        })
+    }
+
+    /// The device's volume including the master volume. It comes from the entry naming
+    /// the device, or from the system default entry for the default device. A device
+    /// standing in for a missing one plays at full volume rather than the missing one's.
+    fn configured_volume(&self, config: &AudioConfig) -> f32 {
+        let named = config
+            .outputs
+            .iter()
+            .find(|output| output.device.as_ref() == Some(&self.device_name));
+        let default = config
+            .outputs
+            .iter()
+            .find(|output| output.device.is_none())
+            .filter(|_| !self.fallback);
+        match named.or(default) {
+            Some(output) => output.effective_volume(config.master_volume),
+            None => config.master_volume.max(0.0),
+        }
+    }
+}
+
+/// The output devices are opened once and shared by every playback path,
+/// so messages don't pay for reopening a device or click between each other.
+/// Everything appended is played on every device at that device's volume.
+/// Methods without a bus act on the TTS bus.
+pub struct AudioOutput {
+    devices: Vec<DeviceOutput>,
+    buses: Mutex<HashMap<Bus, BusConfig>>,
+    /// Scales the TTS bus while a message is faded out
+    fade: Mutex<f32>,
+    /// Scales the ducked buses while TTS is speaking
+    duck: Mutex<f32>,
+    /// Speech sources that are playing right now, counted once per device
+    speech: Arc<AtomicUsize>,
+    /// Volume of each bus on the first device, the one that is recorded, as f32 bits
+    tap_gains: HashMap<Bus, Arc<AtomicU32>>,
+}
+
+/// Counts a source as speech from its first sample until the sink drops it,
+/// so the chime and the gap between messages don't duck the other buses
+struct Speech<S> {
+    inner: S,
+    playing: Arc<AtomicUsize>,
+    started: bool,
+}
+
+impl<S: Source<Item = f32>> Iterator for Speech<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if !self.started {
+            self.started = true;
+            self.playing.fetch_add(1, Ordering::SeqCst);
+        }
+        self.inner.next()
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Speech<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+impl<S> Drop for Speech<S> {
+    fn drop(&mut self) {
+        if self.started {
+            self.playing.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Option<Arc<AudioOutput>>> = Mutex::new(None);
+    static ref AUDIO_CONFIG: Mutex<AudioConfig> = Mutex::new(AudioConfig::default());
+    static ref APP_HANDLE: Mutex<Option<AppHandle>> = Mutex::new(None);
+    /// The decoded background music, looped again whenever the devices are reopened
+    static ref MUSIC: Mutex<Option<AudioBuffer>> = Mutex::new(None);
+    static ref RETRY: Mutex<Retry> = Mutex::new(Retry {
+        delay: RETRY_MIN,
+        next_attempt: None,
+    });
+}
+
+/// Keeps a device that fails to open from being reopened over and over
+struct Retry {
+    delay: Duration,
+    /// Set after a failed open, devices aren't opened again before then
+    next_attempt: Option<Instant>,
+}
+
+impl Retry {
+    fn waiting(&self) -> Option<Duration> {
+        self.next_attempt
+            .and_then(|next_attempt| next_attempt.checked_duration_since(Instant::now()))
+    }
+
+    fn failed(&mut self) {
+        self.next_attempt = Some(Instant::now() + self.delay);
+        self.delay = (self.delay * 2).min(RETRY_MAX);
+    }
+
+    fn succeeded(&mut self) {
+        self.delay = RETRY_MIN;
+        self.next_attempt = None;
+    }
+}
+
+impl AudioOutput {
+    /// Opens every configured device, a device that can't be opened is left out
+    fn open(config: &AudioConfig) -> (AudioOutput, Vec<String>) {
+        let default_output = [OutputConfig::default()];
+        let configs = if config.outputs.is_empty() {
+            &default_output[..]
+        } else {
+            &config.outputs[..]
+        };
+
+        let mut devices: Vec<DeviceOutput> = Vec::new();
+        let mut problems = Vec::new();
+        for output_config in configs {
+            let device = match DeviceOutput::open(output_config) {
+                Ok(device) => device,
+                Err(e) => {
+                    problems.push(e);
+                    continue;
+                }
+            };
+            if let Some(wanted) = output_config.device.as_ref().filter(|_| device.fallback) {
+                problems.push(format!(
+                    "Output device {} is not available, playing on {}",
+                    wanted, device.device_name
+                ));
+            }
+            // a missing device falls back to the default, which may already be playing
+            match devices
+                .iter()
+                .position(|open| open.device_name == device.device_name)
+            {
+                // keep the one opened for its own entry, the default entry's volume applies to it
+                Some(index) if devices[index].fallback && !device.fallback => {
+                    devices[index] = device
+                }
+                Some(_) => {}
+                None => devices.push(device),
+            }
+        }
+        for device in &devices {
+            *device.volume.lock().unwrap() = device.configured_volume(config);
+        }
+        let output = AudioOutput {
+            devices,
+            buses: Mutex::new(BUSES.map(|bus| (bus, config.bus(bus))).into()),
+            fade: Mutex::new(1.0),
+            duck: Mutex::new(1.0),
+            speech: Arc::new(AtomicUsize::new(0)),
+            tap_gains: BUSES
+                .map(|bus| (bus, Arc::new(AtomicU32::new(1f32.to_bits()))))
+                .into(),
+        };
+        for device in &output.devices {
+            output.apply_volume(device);
+        }
+        if let Some(music) = MUSIC.lock().unwrap().as_ref() {
+            output.loop_music(music);
+        }
+        (output, problems)
+    }
+
+    fn device_names(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .map(|device| device.device_name.clone())
+            .collect()
+    }
+
+    fn bus_sinks(&self, bus: Bus) -> impl Iterator<Item = &Sink> {
+        self.devices.iter().map(move |device| &device.sinks[&bus])
+    }
+
+    fn sinks(&self) -> impl Iterator<Item = &Sink> {
+        self.bus_sinks(Bus::Tts)
+    }
+
+    /// Queues speech on the TTS bus
+    pub fn append(&self, audio: &AudioBuffer) {
+        for (i, sink) in self.sinks().enumerate() {
+            let speech = Speech {
+                inner: audio.to_source(),
+                playing: self.speech.clone(),
+                started: false,
+            };
+            if i == 0 {
+                sink.append(recorder::tap_speech(speech, self.tap_gain(Bus::Tts)));
+            } else {
+                sink.append(speech);
+            }
+        }
+    }
+
+    /// Plays a sound over whatever else is playing, unaffected by skipping or pausing speech.
+    /// On the TTS bus the sound is queued behind the speech instead, it doesn't count as
+    /// speaking and doesn't move the utterance's times in a recording.
+    pub fn play_on(&self, bus: Bus, audio: &AudioBuffer) {
+        for (i, sink) in self.bus_sinks(bus).enumerate() {
+            // every device plays the same audio, only record it once
+            if i == 0 {
+                sink.append(recorder::tap(audio.to_source(), self.tap_gain(bus)));
+            } else {
+                sink.append(audio.to_source());
+            }
+        }
+    }
+
+    /// Replaces whatever the music bus is playing with the audio on repeat
+    fn loop_music(&self, music: &AudioBuffer) {
+        for (i, sink) in self.bus_sinks(Bus::Music).enumerate() {
+            sink.stop();
+            let music = music.to_source().repeat_infinite();
+            if i == 0 {
+                sink.append(recorder::tap(music, self.tap_gain(Bus::Music)));
+            } else {
+                sink.append(music);
+            }
+        }
+    }
+
+    fn stop_music(&self) {
+        self.bus_sinks(Bus::Music).for_each(Sink::stop);
+    }
+
+    pub fn append_silence(&self, duration: Duration, sample_rate: u32, channels: u16) {
+        for sink in self.sinks() {
+            sink.append(Zero::<f32>::new(channels, sample_rate).take_duration(duration));
+        }
+    }
+
+    fn apply_volume(&self, device: &DeviceOutput) {
+        let volume = *device.volume.lock().unwrap();
+        let buses = self.buses.lock().unwrap();
+        let fade = *self.fade.lock().unwrap();
+        let duck = *self.duck.lock().unwrap();
+        // only the first device's sources are tapped
+        let recorded = self
+            .devices
+            .first()
+            .is_some_and(|first| std::ptr::eq(first, device));
+        for (bus, sink) in &device.sinks {
+            let config = buses.get(bus).cloned().unwrap_or_default();
+            let level = match bus {
+                Bus::Tts => fade,
+                _ if config.ducked => duck,
+                _ => 1.0,
+            };
+            let gain = volume * config.gain() * level;
+            sink.set_volume(gain);
+            if recorded {
+                self.tap_gains[bus].store(gain.to_bits(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn tap_gain(&self, bus: Bus) -> Arc<AtomicU32> {
+        self.tap_gains[&bus].clone()
+    }
+
+    fn apply_volumes(&self) {
+        for device in &self.devices {
+            self.apply_volume(device);
+        }
+    }
+
+    /// True while speech is coming out of the TTS bus
+    pub fn speaking(&self) -> bool {
+        self.speech.load(Ordering::SeqCst) > 0 && !self.is_paused()
+    }
+
+    /// Scales the volume of the ducked buses, 1.0 is not ducked
+    fn set_duck(&self, level: f32) {
+        *self.duck.lock().unwrap() = level.clamp(0.0, 1.0);
+        self.apply_volumes();
+    }
+
+    /// Scales the volume of the TTS bus on every device, 1.0 is no fade
+    pub fn set_fade(&self, level: f32) {
+        *self.fade.lock().unwrap() = level.clamp(0.0, 1.0);
+        self.apply_volumes();
+    }
+
+    pub fn stop(&self) {
+        self.sinks().for_each(Sink::stop);
+    }
+
+    pub fn pause(&self) {
+        self.sinks().for_each(Sink::pause);
+    }
+
+    pub fn play(&self) {
+        self.sinks().for_each(Sink::play);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.sinks().any(Sink::is_paused)
+    }
+
+    /// True once every device has finished playing
+    pub fn empty(&self) -> bool {
+        self.sinks().all(Sink::empty)
+    }
+
+    /// False once playback has moved to other devices, the sinks then no longer play
+    pub fn is_current(self: &Arc<Self>) -> bool {
+        OUTPUT
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, self))
+    }
+}
+
+pub fn set_app_handle(handle: AppHandle) {
+    *APP_HANDLE.lock().unwrap() = Some(handle);
+}
+
+fn emit_device_change(devices: Vec<String>, message: String) {
+    println!("{}", message);
+    if let Some(handle) = APP_HANDLE.lock().unwrap().as_ref() {
+        if let Err(e) = handle.emit(AUDIO_DEVICE_EVENT, AudioDeviceChange { devices, message }) {
+            println!("Error emitting audio device change: {}", e);
+        }
+    }
+}
+
+/// Names of the output devices that are currently connected
+pub fn list_output_devices() -> Result<Vec<String>, String> {
+    let devices = rodio::cpal::default_host()
+        .output_devices()
+        .map_err(|e| format!("Failed to list output devices: {}", e))?;
+    Ok(devices.filter_map(|device| device.name().ok()).collect())
+}
+
+/// Opens the configured devices in place of the current output
+fn reconnect(mut output: MutexGuard<Option<Arc<AudioOutput>>>) -> Result<Arc<AudioOutput>, String> {
+    // close the old streams first, some devices can only be opened once
+    *output = None;
+    let config = AUDIO_CONFIG.lock().unwrap().clone();
+    let (opened, problems) = AudioOutput::open(&config);
+    let devices = opened.device_names();
+    // a configured device that couldn't be opened is tried again later, not on every check
+    if problems.is_empty() {
+        RETRY.lock().unwrap().succeeded();
+    } else {
+        RETRY.lock().unwrap().failed();
+    }
+    if devices.is_empty() {
+        let message = match problems.last() {
+            Some(problem) => problem.clone(),
+            None => "No audio output device available".to_string(),
+        };
+        emit_device_change(devices, message.clone());
+        return Err(message);
+    }
+
+    let opened = Arc::new(opened);
+    *output = Some(opened.clone());
+    drop(output);
+    let mut message = format!("Playing on {}", devices.join(", "));
+    for problem in problems {
+        message = format!("{}. {}", problem, message);
+    }
+    emit_device_change(devices, message);
+    Ok(opened)
+}
+
+/// Returns the shared output, opening the devices the first time they are needed
+pub fn output() -> Result<Arc<AudioOutput>, String> {
+    let output = OUTPUT.lock().unwrap();
+    if let Some(output) = output.as_ref() {
+        return Ok(output.clone());
+    }
+    if let Some(wait) = RETRY.lock().unwrap().waiting() {
+        return Err(format!(
+            "No audio output available, trying again in {}s",
+            wait.as_secs() + 1
+        ));
+    }
+    reconnect(output)
+}
+
+/// Watches for output devices being unplugged, or configured devices coming back,
+/// and moves playback over to the right devices
+pub fn start_device_monitor() {
+    thread::spawn(|| loop {
+        thread::sleep(DEVICE_CHECK_INTERVAL);
+        // nothing has played yet, the devices are picked when they're first needed
+        let checked = match OUTPUT.lock().unwrap().as_ref() {
+            Some(current) => current.clone(),
+            None => continue,
+        };
+        let current = checked.device_names();
+        // listing devices can be slow, don't hold up playback while it runs
+        let connected = match list_output_devices() {
+            Ok(connected) => connected,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        let lost: Vec<&String> = current
+            .iter()
+            .filter(|device| !connected.contains(device))
+            .collect();
+        let configs = AUDIO_CONFIG.lock().unwrap().outputs.clone();
+        let returned = configs.iter().any(|config| {
+            config
+                .device
+                .as_ref()
+                .is_some_and(|wanted| !current.contains(wanted) && connected.contains(wanted))
+        }) && RETRY.lock().unwrap().waiting().is_none();
+        if !lost.is_empty() || returned {
+            let output = OUTPUT.lock().unwrap();
+            // the output was replaced while we were looking, the next check looks at the new one
+            if !output
+                .as_ref()
+                .is_some_and(|output| Arc::ptr_eq(output, &checked))
+            {
+                continue;
+            }
+            for device in lost {
+                println!("Output device {} was disconnected", device);
+            }
+            if let Err(e) = reconnect(output) {
+                println!("Error reconnecting audio output: {}", e);
+            }
+        }
+    });
+}
+
+pub fn set_audio_config(config: AudioConfig) {
+    let mut audio_config = AUDIO_CONFIG.lock().unwrap();
+    let old_devices: Vec<Option<String>> = audio_config
+        .outputs
+        .iter()
+        .map(|output| output.device.clone())
+        .collect();
+    *audio_config = config.clone();
+    drop(audio_config);
+
+    // only reopen if something has played already, otherwise the next message opens them
+    let output = OUTPUT.lock().unwrap();
+    let current = match output.as_ref() {
+        Some(current) => current.clone(),
+        None => return,
+    };
+    let new_devices: Vec<Option<String>> = config
+        .outputs
+        .iter()
+        .map(|output| output.device.clone())
+        .collect();
+    if old_devices != new_devices {
+        if let Err(e) = reconnect(output) {
+            println!("Error switching audio output: {}", e);
+        }
+        return;
+    }
+
+    // same devices, volume and mute changes apply to what is playing right away
+    drop(output);
+    *current.buses.lock().unwrap() = BUSES.map(|bus| (bus, config.bus(bus))).into();
+    for device in &current.devices {
+        *device.volume.lock().unwrap() = device.configured_volume(&config);
+        current.apply_volume(device);
+    }
+}
+
+/// How long to wait between messages
+pub fn message_gap() -> Duration {
+    Duration::from_millis(AUDIO_CONFIG.lock().unwrap().message_gap_ms)
+}
+
+/// Loops the audio on the music bus, or stops the music when there is none
+pub fn set_music(music: Option<AudioBuffer>) {
+    let output = OUTPUT.lock().unwrap();
+    *MUSIC.lock().unwrap() = music.clone();
+    if let Some(current) = output.as_ref() {
+        match music.as_ref() {
+            Some(music) => current.loop_music(music),
+            None => current.stop_music(),
+        }
+    }
+}
+
+/// Turns the ducked buses down while TTS is speaking and back up once it stops
+pub fn start_ducking() {
+    thread::spawn(|| {
+        let mut last_tick = Instant::now();
+        loop {
+            thread::sleep(DUCK_INTERVAL);
+            let elapsed = last_tick.elapsed();
+            last_tick = Instant::now();
+
+            let output = match OUTPUT.lock().unwrap().as_ref() {
+                Some(output) => output.clone(),
+                None => continue,
+            };
+            let ducking = AUDIO_CONFIG.lock().unwrap().ducking.clone();
+            let ducked = dsp::db_to_gain(ducking.level_db.min(0.0));
+            let current = *output.duck.lock().unwrap();
+            let (target, ramp_ms) = if ducking.enabled && output.speaking() {
+                (ducked, ducking.attack_ms)
+            } else {
+                (1.0, ducking.release_ms)
+            };
+            if current == target {
+                continue;
+            }
+            // move linearly so a full duck takes the attack or release time
+            let step = if ramp_ms == 0 {
+                1.0
+            } else {
+                (1.0 - ducked) * elapsed.as_secs_f32() * 1000.0 / ramp_ms as f32
+            };
+            let next = if current > target {
+                (current - step).max(target)
+            } else {
+                (current + step).min(target)
+            };
+            output.set_duck(next);
+        }
+    });
+}
@@ -1,32 +1,83 @@
 use anyhow::Result;
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use tauri::{AppHandle, Emitter};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::alerts;
+use crate::commands::{ChatCommand, CHAT_COMMAND_EVENT};
+use crate::queue::TTS_QUEUE;
+use crate::ratelimit::RATE_LIMITER;
+use crate::soundboard;
+use crate::spam::SPAM_FILTER;
 
 const SERVER: &str = "irc.chat.twitch.tv";
-const PORT: u16 = 6667;
+/// The TLS port, so the OAuth token never crosses the network in plain text
+const PORT: u16 = 6697;
 const DEFAULT_NICKNAME: &str = "justinfan12345";
 
 lazy_static! {
     static ref DISPLAY_NAME_REGEX: Regex = Regex::new(r"display-name=([^;]+)").unwrap();
     static ref USERNAME_REGEX: Regex = Regex::new(r":([^!]+)!").unwrap();
     static ref MESSAGE_REGEX: Regex = Regex::new(r"PRIVMSG [^:]+:(.+)").unwrap();
+    static ref USER_NOTICE_REGEX: Regex = Regex::new(r"USERNOTICE #\S+(?: :(.+))?").unwrap();
+}
+
+/// Login for an account that can send messages, such as command replies
+#[derive(Debug, Clone)]
+pub struct ChatAuth {
+    pub nickname: String,
+    pub oauth_token: String,
+}
+
+pub type ChatStream = TlsStream<TcpStream>;
+
+/// Opens a TLS connection to the Twitch IRC server, checked against the bundled root certificates
+async fn connect_tls() -> Result<ChatStream> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let stream = TcpStream::connect((SERVER, PORT)).await?;
+    let domain = ServerName::try_from(SERVER)?;
+    Ok(connector.connect(domain, stream).await?)
 }
 
-pub async fn connect_to_twitch_chat(channel: &str, nickname: Option<&str>) -> Result<TcpStream> {
+pub async fn connect_to_twitch_chat(channel: &str, auth: Option<&ChatAuth>) -> Result<ChatStream> {
     // Connect to the Twitch IRC server
-    let mut stream = TcpStream::connect((SERVER, PORT)).await?;
+    let mut stream = connect_tls().await?;
 
     // Send authentication info
     // For anonymous connection, we can use "justinfan" followed by any number
-    stream.write_all(b"PASS SCHMOOPIIE\r\n").await?;
-    stream
-        .write_all(format!("NICK {}\r\n", nickname.unwrap_or(DEFAULT_NICKNAME)).as_bytes())
-        .await?;
+    match auth {
+        Some(auth) => {
+            let token = auth.oauth_token.trim_start_matches("oauth:");
+            stream
+                .write_all(format!("PASS oauth:{}\r\n", token).as_bytes())
+                .await?;
+            stream
+                .write_all(format!("NICK {}\r\n", auth.nickname.to_lowercase()).as_bytes())
+                .await?;
+        }
+        None => {
+            stream.write_all(b"PASS SCHMOOPIIE\r\n").await?;
+            stream
+                .write_all(format!("NICK {}\r\n", DEFAULT_NICKNAME).as_bytes())
+                .await?;
+        }
+    }
     stream
         .write_all(format!("JOIN #{}\r\n", channel.trim_start_matches('#')).as_bytes())
         .await?;
@@ -40,22 +91,127 @@ pub async fn connect_to_twitch_chat(channel: &str, nickname: Option<&str>) -> Re
     stream.flush().await?;
 
     // Print connection message
-    println!(
-        "Connected to #{} chat as anonymous viewer.",
-        channel.trim_start_matches('#')
-    );
+    match auth {
+        Some(auth) => println!(
+            "Connected to #{} chat as {}.",
+            channel.trim_start_matches('#'),
+            auth.nickname
+        ),
+        None => println!(
+            "Connected to #{} chat as anonymous viewer.",
+            channel.trim_start_matches('#')
+        ),
+    }
     println!("Press Ctrl+C to exit");
 
     Ok(stream)
 }
 
+/// The highest badge a chatter has, in increasing order of importance
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum UserRole {
+    Viewer,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+/// What kind of chat event a message came from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Chat,
+    Cheer,
+    Redemption,
+    Raid,
+    Subscription,
+    /// Generated by the app itself, such as queue summaries
+    System,
+    /// A soundboard clip waiting for its turn, the text is the clip name
+    Sound,
+    /// Text typed into the app by the streamer
+    Manual,
+}
+
 #[derive(Debug)]
 pub struct ChatMessage {
     pub username: String,
     pub content: String,
+    pub role: UserRole,
+    pub kind: MessageKind,
+    pub bits: u32,
+    /// Channel point reward the message was sent with
+    pub reward_id: Option<String>,
+    /// The user's first ever message in the channel
+    pub first_message: bool,
+    /// Twitch's description of a raid or sub, which `content` starts with.
+    /// The rest of `content` is the user's own message.
+    pub notice: Option<String>,
+}
+
+/// Splits the IRCv3 tags at the start of a line into key/value pairs
+fn parse_tags(message: &str) -> HashMap<&str, &str> {
+    message
+        .strip_prefix('@')
+        .and_then(|rest| rest.split(' ').next())
+        .map(|tags| {
+            tags.split(';')
+                .filter_map(|tag| tag.split_once('='))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reverses the escaping Twitch applies to tag values such as system-msg
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some(':') => unescaped.push(';'),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+fn parse_role(tags: &HashMap<&str, &str>) -> UserRole {
+    let badges = tags.get("badges").copied().unwrap_or_default();
+    let has_badge = |name: &str| {
+        badges
+            .split(',')
+            .any(|badge| badge.split('/').next() == Some(name))
+    };
+
+    if has_badge("broadcaster") {
+        UserRole::Broadcaster
+    } else if has_badge("moderator") || tags.get("mod") == Some(&"1") {
+        UserRole::Moderator
+    } else if has_badge("vip") {
+        UserRole::Vip
+    } else if has_badge("subscriber")
+        || has_badge("founder")
+        || tags.get("subscriber") == Some(&"1")
+    {
+        UserRole::Subscriber
+    } else {
+        UserRole::Viewer
+    }
 }
 
 pub fn parse_message(message: &str) -> Option<ChatMessage> {
+    let tags = parse_tags(message);
+
     // Try to get display name first
     let username = if let Some(cap) = DISPLAY_NAME_REGEX.captures(message) {
         cap.get(1).map(|m| m.as_str().to_string())
@@ -65,19 +221,73 @@ pub fn parse_message(message: &str) -> Option<ChatMessage> {
             .captures(message)
             .and_then(|cap| cap.get(1))
             .map(|m| m.as_str().to_string())
-    };
+    }?;
+    let role = parse_role(&tags);
+    let bits = tags
+        .get("bits")
+        .and_then(|bits| bits.parse().ok())
+        .unwrap_or(0);
+    let reward_id = tags.get("custom-reward-id").map(|id| id.to_string());
+    let first_message = tags.get("first-msg") == Some(&"1");
 
     // Get message content
-    let content = MESSAGE_REGEX
+    if let Some(content) = MESSAGE_REGEX
         .captures(message)
         .and_then(|cap| cap.get(1))
-        .map(|m| m.as_str().trim().to_string());
+        .map(|m| m.as_str().trim().to_string())
+    {
+        let kind = if reward_id.is_some() {
+            MessageKind::Redemption
+        } else if bits > 0 {
+            MessageKind::Cheer
+        } else {
+            MessageKind::Chat
+        };
+        return Some(ChatMessage {
+            username,
+            content,
+            role,
+            kind,
+            bits,
+            reward_id,
+            first_message,
+            notice: None,
+        });
+    }
 
-    // Return Some only if we have both username and content
-    match (username, content) {
-        (Some(username), Some(content)) => Some(ChatMessage { username, content }),
-        _ => None,
+    // Raids and subs arrive as USERNOTICEs, read out Twitch's own description of the event
+    let cap = USER_NOTICE_REGEX.captures(message)?;
+    let kind = match tags.get("msg-id").copied() {
+        Some("raid") => MessageKind::Raid,
+        Some("sub" | "resub" | "subgift" | "submysterygift" | "anonsubgift") => {
+            MessageKind::Subscription
+        }
+        _ => return None,
+    };
+    let system_message = tags
+        .get("system-msg")
+        .map(|msg| unescape_tag_value(msg))
+        .unwrap_or_default();
+    let content = match cap.get(1).map(|m| m.as_str().trim()) {
+        Some(user_message) if !user_message.is_empty() => {
+            format!("{} {}", system_message, user_message)
+        }
+        _ => system_message.clone(),
+    };
+    if content.is_empty() {
+        return None;
     }
+
+    Some(ChatMessage {
+        username,
+        content,
+        role,
+        kind,
+        bits,
+        reward_id,
+        first_message,
+        notice: Some(system_message),
+    })
 }
 
 pub async fn test_function(channel: &str) -> Result<()> {
@@ -121,10 +331,11 @@ pub async fn test_function(channel: &str) -> Result<()> {
 
 pub async fn start_twitch_chat_reader(
     channel: &str,
-    tts_tx: &Sender<String>,
+    auth: Option<ChatAuth>,
     kill_flag: &Arc<AtomicBool>,
+    app_handle: AppHandle,
 ) -> Result<()> {
-    let stream = connect_to_twitch_chat(channel, None).await?;
+    let stream = connect_to_twitch_chat(channel, auth.as_ref()).await?;
     let mut reader = BufReader::new(stream);
     let mut line = String::new();
 
@@ -157,15 +368,73 @@ pub async fn start_twitch_chat_reader(
 
                 if let Some(message) = parse_message(&line) {
                     println!("{}: {}", message.username, message.content);
-                    match tts_tx.send(format!(
-                        "user {} said {}",
-                        message.username, message.content
-                    )) {
-                        Ok(_) => {}
-                        Err(_) => {
-                            println!("Invalid tts_tx, another twitch_chat_reader is likely running, killing self");
-                            break;
+
+                    // commands are handled here and never read out
+                    if let Some(command) = ChatCommand::parse(&message.content) {
+                        let outcome = command.run(&message, &app_handle).await;
+                        println!(
+                            "Command {} from {}: {}",
+                            outcome.command, outcome.user, outcome.message
+                        );
+                        if auth.is_some() {
+                            let reply = format!(
+                                "PRIVMSG #{} :@{} {}\r\n",
+                                channel.trim_start_matches('#'),
+                                message.username,
+                                outcome.message
+                            );
+                            // a failed reply shouldn't stop the messages from being read
+                            let stream = reader.get_mut();
+                            let sent = match stream.write_all(reply.as_bytes()).await {
+                                Ok(()) => stream.flush().await,
+                                Err(e) => Err(e),
+                            };
+                            if let Err(e) = sent {
+                                println!("Error replying to {}: {}", message.username, e);
+                            }
+                        }
+                        if let Err(e) = app_handle.emit(CHAT_COMMAND_EVENT, outcome) {
+                            println!("Error emitting chat command: {}", e);
                         }
+                        continue;
+                    }
+                    // redemptions play their clip even when the text isn't read
+                    let played_clip = match &message.reward_id {
+                        Some(reward_id) => {
+                            soundboard::on_redemption(reward_id, &message.username).await
+                        }
+                        None => false,
+                    };
+
+                    let checked = SPAM_FILTER.lock().unwrap().check(&message);
+                    let spam_match = match checked {
+                        Ok(spam_match) => spam_match,
+                        Err(rejection) => {
+                            if let Some(id) = &rejection.repeat_of {
+                                TTS_QUEUE.add_repeat(id);
+                            }
+                            println!(
+                                "Not reading message from {}: {}",
+                                message.username, rejection.reason
+                            );
+                            continue;
+                        }
+                    };
+                    // merged messages count too, or quick messages could grow one item forever
+                    let queued = TTS_QUEUE.pending_for_user(&message.username);
+                    let allowed = RATE_LIMITER.lock().unwrap().check(&message, queued);
+                    if let Err(reason) = allowed {
+                        println!("Not reading message from {}: {}", message.username, reason);
+                        continue;
+                    }
+                    let id = match TTS_QUEUE.merge_message(&message) {
+                        Ok(id) => id,
+                        Err(message) => TTS_QUEUE.push_message(message),
+                    };
+                    SPAM_FILTER.lock().unwrap().attach(spam_match, &id);
+                    // the reward's own clip already played, it wins over the redemption alert
+                    if !played_clip {
+                        alerts::on_message(&message);
                     }
                 }
             }
@@ -0,0 +1,233 @@
+use crate::chat::{ChatMessage, UserRole};
+use crate::control::PLAYBACK;
+use crate::queue::TTS_QUEUE;
+use crate::soundboard;
+use serde::Serialize;
+use tauri::AppHandle;
+
+const TTS_USAGE: &str =
+    "usage: !tts skip | pause | resume | clear | mute <user> | unmute <user> | voice <id>";
+
+/// Event emitted with a `CommandOutcome` whenever a chat command is handled
+pub const CHAT_COMMAND_EVENT: &str = "tts-chat-command";
+
+/// Commands viewers can type in chat to control TTS
+#[derive(Debug, PartialEq)]
+pub enum ChatCommand {
+    /// `!ttscancel [user]`, removes the sender's pending messages,
+    /// mods can name another user
+    Cancel { target: Option<String> },
+    /// `!tts <action>`, mod only remote control of playback
+    Tts(TtsAction),
+    /// `!sound <name>`, plays a soundboard clip
+    Sound { name: Option<String> },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TtsAction {
+    Skip,
+    Pause,
+    Resume,
+    Clear,
+    Mute(String),
+    Unmute(String),
+    Voice(i32),
+    /// Anything we couldn't parse, answered with the usage text
+    Invalid,
+}
+
+/// What happened when a chat command was run, sent to the UI
+#[derive(Serialize, Clone, Debug)]
+pub struct CommandOutcome {
+    pub user: String,
+    pub command: String,
+    pub success: bool,
+    pub message: String,
+}
+
+fn is_mod(message: &ChatMessage) -> bool {
+    message.role >= UserRole::Moderator
+}
+
+impl ChatCommand {
+    /// Parses a chat message, returns None if it isn't a TTS command
+    pub fn parse(content: &str) -> Option<ChatCommand> {
+        let mut words = content.split_whitespace();
+        let name = words.next()?.to_lowercase();
+        match name.as_str() {
+            "!ttscancel" => Some(ChatCommand::Cancel {
+                target: words
+                    .next()
+                    .map(|user| user.trim_start_matches('@').to_string()),
+            }),
+            "!tts" => {
+                let action = words.next().map(str::to_lowercase);
+                let argument = words.next();
+                let action = match (action.as_deref(), argument) {
+                    (Some("skip"), _) => TtsAction::Skip,
+                    (Some("pause"), _) => TtsAction::Pause,
+                    (Some("resume"), _) => TtsAction::Resume,
+                    (Some("clear"), _) => TtsAction::Clear,
+                    (Some("mute"), Some(user)) => TtsAction::Mute(user.to_string()),
+                    (Some("unmute"), Some(user)) => TtsAction::Unmute(user.to_string()),
+                    (Some("voice"), Some(id)) => match id.parse() {
+                        Ok(id) => TtsAction::Voice(id),
+                        Err(_) => TtsAction::Invalid,
+                    },
+                    _ => TtsAction::Invalid,
+                };
+                Some(ChatCommand::Tts(action))
+            }
+            "!sound" => Some(ChatCommand::Sound {
+                name: words.next().map(str::to_string),
+            }),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ChatCommand::Cancel { .. } => "ttscancel",
+            ChatCommand::Tts(_) => "tts",
+            ChatCommand::Sound { .. } => "sound",
+        }
+    }
+
+    /// Runs the command on behalf of the sender and returns a reply for chat
+    pub async fn run(&self, message: &ChatMessage, app: &AppHandle) -> CommandOutcome {
+        let result = match self {
+            ChatCommand::Cancel { target } => cancel(message, target.as_deref()),
+            ChatCommand::Tts(_) if !is_mod(message) => Err("only mods can control TTS".to_string()),
+            ChatCommand::Tts(action) => run_tts_action(action, app).await,
+            ChatCommand::Sound { name: Some(name) } => {
+                soundboard::request(name, &message.username, message.role).await
+            }
+            ChatCommand::Sound { name: None } => Err("usage: !sound <name>".to_string()),
+        };
+        let (success, reply) = match result {
+            Ok(reply) => (true, reply),
+            Err(reply) => (false, reply),
+        };
+        CommandOutcome {
+            user: message.username.clone(),
+            command: self.name().to_string(),
+            success,
+            message: reply,
+        }
+    }
+}
+
+fn cancel(message: &ChatMessage, target: Option<&str>) -> Result<String, String> {
+    let own_messages = match target {
+        Some(target) => target.eq_ignore_ascii_case(&message.username),
+        None => true,
+    };
+    if !own_messages && !is_mod(message) {
+        return Err("only mods can cancel other users' messages".to_string());
+    }
+
+    let user = target.unwrap_or(&message.username);
+    let removed = TTS_QUEUE.remove_user(user);
+    match (removed, own_messages) {
+        (0, true) => Err("you have no queued messages".to_string()),
+        (0, false) => Err(format!("{} has no queued messages", user)),
+        (removed, true) => Ok(format!("removed {} of your queued messages", removed)),
+        (removed, false) => Ok(format!("removed {} queued messages from {}", removed, user)),
+    }
+}
+
+/// Runs a mod command through the same controls the UI uses
+async fn run_tts_action(action: &TtsAction, app: &AppHandle) -> Result<String, String> {
+    match action {
+        TtsAction::Skip => {
+            PLAYBACK.skip();
+            Ok("skipped the current message".to_string())
+        }
+        TtsAction::Pause => {
+            PLAYBACK.pause();
+            Ok("TTS paused".to_string())
+        }
+        TtsAction::Resume => {
+            PLAYBACK.resume();
+            Ok("TTS resumed".to_string())
+        }
+        TtsAction::Clear => {
+            let removed = TTS_QUEUE.clear();
+            Ok(format!("cleared {} queued messages", removed))
+        }
+        TtsAction::Mute(user) => {
+            let removed = crate::mute_user(app, user)?;
+            Ok(format!(
+                "muted {} and removed {} queued messages",
+                user, removed
+            ))
+        }
+        TtsAction::Unmute(user) => {
+            if crate::unmute_user(app, user)? {
+                Ok(format!("unmuted {}", user))
+            } else {
+                Err(format!("{} is not muted", user))
+            }
+        }
+        TtsAction::Voice(id) => {
+            // checking the voice and saving it touch the disk, keep them off the chat task
+            let (app, id) = (app.clone(), *id);
+            tokio::task::spawn_blocking(move || crate::apply_speaker(&app, id))
+                .await
+                .map_err(|e| e.to_string())??;
+            Ok(format!("voice changed to {}", id))
+        }
+        TtsAction::Invalid => Err(TTS_USAGE.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let cases = [
+            ("hello !tts skip", None),
+            ("!ttsskip", None),
+            ("!ttscancel", Some(ChatCommand::Cancel { target: None })),
+            (
+                "!TTSCancel @SomeViewer",
+                Some(ChatCommand::Cancel {
+                    target: Some("SomeViewer".to_string()),
+                }),
+            ),
+            ("!tts skip", Some(ChatCommand::Tts(TtsAction::Skip))),
+            ("  !TTS   Pause  ", Some(ChatCommand::Tts(TtsAction::Pause))),
+            ("!tts resume now", Some(ChatCommand::Tts(TtsAction::Resume))),
+            ("!tts clear", Some(ChatCommand::Tts(TtsAction::Clear))),
+            (
+                "!tts mute @Spammer",
+                Some(ChatCommand::Tts(TtsAction::Mute("@Spammer".to_string()))),
+            ),
+            (
+                "!tts unmute spammer",
+                Some(ChatCommand::Tts(TtsAction::Unmute("spammer".to_string()))),
+            ),
+            ("!tts voice 3", Some(ChatCommand::Tts(TtsAction::Voice(3)))),
+            ("!tts", Some(ChatCommand::Tts(TtsAction::Invalid))),
+            ("!tts mute", Some(ChatCommand::Tts(TtsAction::Invalid))),
+            ("!tts voice", Some(ChatCommand::Tts(TtsAction::Invalid))),
+            (
+                "!tts voice loud",
+                Some(ChatCommand::Tts(TtsAction::Invalid)),
+            ),
+            ("!tts dance", Some(ChatCommand::Tts(TtsAction::Invalid))),
+            ("!sound", Some(ChatCommand::Sound { name: None })),
+            (
+                "!Sound airhorn",
+                Some(ChatCommand::Sound {
+                    name: Some("airhorn".to_string()),
+                }),
+            ),
+        ];
+        for (content, expected) in cases {
+            assert_eq!(ChatCommand::parse(content), expected, "{:?}", content);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::audio::AudioBuffer;
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Playback controls shared between the UI commands, global hotkeys and the audio loop.
+/// The audio loop polls these flags while it is playing, so every setter returns immediately.
+pub struct PlaybackControl {
+    skip: AtomicBool,
+    paused: AtomicBool,
+    replay: AtomicBool,
+    muted_until: Mutex<Option<Instant>>,
+    last_played: Mutex<Option<AudioBuffer>>,
+}
+
+lazy_static! {
+    pub static ref PLAYBACK: PlaybackControl = PlaybackControl {
+        skip: AtomicBool::new(false),
+        paused: AtomicBool::new(false),
+        replay: AtomicBool::new(false),
+        muted_until: Mutex::new(None),
+        last_played: Mutex::new(None),
+    };
+}
+
+impl PlaybackControl {
+    /// Stops the message that is currently playing
+    pub fn skip(&self) {
+        self.skip.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once for every skip request
+    pub fn take_skip(&self) -> bool {
+        self.skip.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Flips the paused state and returns the new value
+    pub fn toggle_pause(&self) -> bool {
+        !self.paused.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Drops every message until the duration has passed, including the one playing now
+    pub fn mute_for(&self, duration: Duration) {
+        *self.muted_until.lock().unwrap() = Some(Instant::now() + duration);
+        self.skip();
+    }
+
+    pub fn unmute(&self) {
+        *self.muted_until.lock().unwrap() = None;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        let mut muted_until = self.muted_until.lock().unwrap();
+        match *muted_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                // the mute expired, clear it so we don't check again
+                *muted_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Asks the audio loop to play the last message again
+    pub fn replay_last(&self) {
+        self.replay.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the last played audio if a replay was requested
+    pub fn take_replay(&self) -> Option<AudioBuffer> {
+        if self.replay.swap(false, Ordering::SeqCst) {
+            self.last_played.lock().unwrap().clone()
+        } else {
+            None
+        }
+    }
+
+    pub fn set_last_played(&self, audio: AudioBuffer) {
+        *self.last_played.lock().unwrap() = Some(audio);
+    }
+}
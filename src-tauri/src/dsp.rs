@@ -0,0 +1,787 @@
+use crate::audio::AudioBuffer;
+use crate::effects::{ActiveEffect, Effect};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{PI, SQRT_2};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Length of the grains used by the pitch shifter
+const GRAIN_SECS: f32 = 0.04;
+/// Loudness is measured over blocks this long, as in ITU-R BS.1770
+const LOUDNESS_BLOCK_SECS: f32 = 0.4;
+const LOUDNESS_STEP_SECS: f32 = 0.1;
+/// Blocks quieter than this never count towards the loudness
+const ABSOLUTE_GATE_LUFS: f32 = -70.0;
+/// Blocks this far below the ungated loudness are ignored too
+const RELATIVE_GATE_LU: f32 = 10.0;
+
+/// Clean-up applied to every synthesized utterance before it is played
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProcessingConfig {
+    pub trim_silence: bool,
+    /// Samples quieter than this count as silence
+    pub silence_threshold_db: f32,
+    /// Silence kept at each end of a trimmed utterance so sentences don't run together
+    pub keep_silence_ms: u64,
+    pub normalize: bool,
+    /// Loudness every utterance is brought to
+    pub target_lufs: f32,
+    /// Limits how much quiet audio is boosted, so near silence isn't turned into noise
+    pub max_gain_db: f32,
+    pub limiter: bool,
+    /// Peaks are kept below this level
+    pub limiter_ceiling_db: f32,
+    /// How quickly the limiter lets go after a peak
+    pub limiter_release_ms: f32,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        ProcessingConfig {
+            trim_silence: true,
+            silence_threshold_db: -50.0,
+            keep_silence_ms: 80,
+            normalize: true,
+            target_lufs: -16.0,
+            max_gain_db: 20.0,
+            limiter: true,
+            limiter_ceiling_db: -1.0,
+            limiter_release_ms: 50.0,
+        }
+    }
+}
+
+lazy_static! {
+    static ref PROCESSING: Mutex<ProcessingConfig> = Mutex::new(ProcessingConfig::default());
+}
+
+pub fn set_processing_config(config: ProcessingConfig) {
+    *PROCESSING.lock().unwrap() = config;
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Splits interleaved samples into one Vec per channel
+pub fn deinterleave(audio: &AudioBuffer) -> Vec<Vec<f32>> {
+    let channels = audio.channels.max(1) as usize;
+    (0..channels)
+        .map(|channel| {
+            audio
+                .samples
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect()
+        })
+        .collect()
+}
+
+pub fn interleave(channels: &[Vec<f32>]) -> Vec<f32> {
+    let frames = channels.iter().map(Vec::len).min().unwrap_or(0);
+    let mut samples = Vec::with_capacity(frames * channels.len());
+    for frame in 0..frames {
+        for channel in channels {
+            samples.push(channel[frame]);
+        }
+    }
+    samples
+}
+
+/// Resamples a signal to exactly `len` samples with linear interpolation
+pub fn resample_to_len(input: &[f32], len: usize) -> Vec<f32> {
+    if input.is_empty() || len == 0 {
+        return vec![0.0; len];
+    }
+    let step = input.len() as f32 / len as f32;
+    (0..len)
+        .map(|i| {
+            let pos = i as f32 * step;
+            let index = pos as usize;
+            let fraction = pos - index as f32;
+            let current = input[index.min(input.len() - 1)];
+            let next = input[(index + 1).min(input.len() - 1)];
+            current + (next - current) * fraction
+        })
+        .collect()
+}
+
+/// Shifts the pitch of one channel by a number of semitones while keeping the duration,
+/// by stretching it in time with overlapping Hann windowed grains and resampling it back.
+/// Grains and the resampler position carry over from one piece to the next, so an utterance
+/// shifted a sentence at a time sounds the same as one shifted in one go. The output lags
+/// the input by about a grain, `finish` returns the rest.
+pub struct PitchShifter {
+    /// How much faster the stretched signal is read back, 1.0 leaves the audio as it is
+    ratio: f64,
+    window: Vec<f32>,
+    /// Distance between grains in the stretched signal
+    hop: usize,
+    /// Input still needed by later grains, starting at sample `input_start`
+    input: Vec<f32>,
+    input_start: usize,
+    /// Overlap-added grains not yet read back, starting at sample `stretched_start`
+    stretched: Vec<f32>,
+    stretched_start: usize,
+    grains: usize,
+    /// Samples taken in and given out so far, the same once finished
+    taken: usize,
+    given: usize,
+}
+
+impl PitchShifter {
+    pub fn new(semitones: f32, sample_rate: u32) -> PitchShifter {
+        let ratio = if semitones.abs() < 0.01 {
+            1.0
+        } else {
+            2f64.powf(semitones as f64 / 12.0)
+        };
+        let grain = ((sample_rate as f32 * GRAIN_SECS) as usize).max(2);
+        // a periodic Hann window at 50% overlap sums to one, so the level stays the same
+        let window = (0..grain)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / grain as f32).cos())
+            .collect();
+        PitchShifter {
+            ratio,
+            window,
+            hop: (grain / 2).max(1),
+            input: Vec::new(),
+            input_start: 0,
+            stretched: Vec::new(),
+            stretched_start: 0,
+            grains: 0,
+            taken: 0,
+            given: 0,
+        }
+    }
+
+    /// Shifts the next piece, returns as much output as the grains so far allow
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.ratio == 1.0 {
+            return input.to_vec();
+        }
+        self.input.extend_from_slice(input);
+        self.taken += input.len();
+        self.add_grains(false);
+        self.read(false)
+    }
+
+    /// Returns the output still held back once the input has ended
+    pub fn finish(&mut self) -> Vec<f32> {
+        if self.ratio == 1.0 {
+            return Vec::new();
+        }
+        self.add_grains(true);
+        self.read(true)
+    }
+
+    /// Where grain `index` starts reading the input
+    fn grain_input(&self, index: usize) -> usize {
+        (index as f64 * self.hop as f64 / self.ratio) as usize
+    }
+
+    /// Overlap-adds every grain the input is long enough for. At the end the input
+    /// is padded with silence until the stretched signal covers all of the output.
+    fn add_grains(&mut self, last: bool) {
+        let grain = self.window.len();
+        let input_end = self.input_start + self.input.len();
+        let stretched_len = (self.taken as f64 * self.ratio).ceil() as usize + 1;
+        loop {
+            let input_pos = self.grain_input(self.grains);
+            let output_pos = self.grains * self.hop;
+            let ready = if last {
+                output_pos < stretched_len
+            } else {
+                input_pos + grain <= input_end
+            };
+            if !ready {
+                break;
+            }
+            let offset = output_pos - self.stretched_start;
+            if self.stretched.len() < offset + grain {
+                self.stretched.resize(offset + grain, 0.0);
+            }
+            for (i, weight) in self.window.iter().enumerate() {
+                let sample = self
+                    .input
+                    .get(input_pos + i - self.input_start)
+                    .copied()
+                    .unwrap_or(0.0);
+                self.stretched[offset + i] += sample * weight;
+            }
+            self.grains += 1;
+        }
+        // input before the next grain is never read again
+        let used = self.grain_input(self.grains).min(input_end) - self.input_start;
+        self.input.drain(..used);
+        self.input_start += used;
+    }
+
+    /// Resamples the finished part of the stretched signal back to the input's length
+    fn read(&mut self, last: bool) -> Vec<f32> {
+        // later grains only add from their own start onwards
+        let complete = if last {
+            usize::MAX
+        } else {
+            self.grains * self.hop
+        };
+        let mut output = Vec::new();
+        while self.given < self.taken {
+            let pos = self.given as f64 * self.ratio;
+            let index = pos as usize;
+            if index + 1 >= complete {
+                break;
+            }
+            let fraction = (pos - index as f64) as f32;
+            let at = |index: usize| {
+                self.stretched
+                    .get(index - self.stretched_start)
+                    .copied()
+                    .unwrap_or(0.0)
+            };
+            let (current, next) = (at(index), at(index + 1));
+            output.push(current + (next - current) * fraction);
+            self.given += 1;
+        }
+        let used = ((self.given as f64 * self.ratio) as usize)
+            .saturating_sub(self.stretched_start)
+            .min(self.stretched.len());
+        self.stretched.drain(..used);
+        self.stretched_start += used;
+        output
+    }
+}
+
+/// Places the audio in the stereo field with a constant power pan law, -1.0 is
+/// hard left and 1.0 hard right. Audio that isn't mono is mixed down first.
+/// The law is scaled so the centre is at unity on both channels, the same level mono
+/// audio plays at, which puts a hard panned side at +3 dB.
+pub fn pan(audio: &AudioBuffer, position: f32) -> AudioBuffer {
+    let channels = deinterleave(audio);
+    let count = channels.len().max(1) as f32;
+    let frames = channels.first().map_or(0, Vec::len);
+    let mono = (0..frames).map(|i| channels.iter().map(|channel| channel[i]).sum::<f32>() / count);
+
+    let angle = (position.clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+    let (left, right) = (angle.cos() * SQRT_2, angle.sin() * SQRT_2);
+    let samples = mono
+        .flat_map(|sample| [sample * left, sample * right])
+        .collect();
+    AudioBuffer::new(samples, audio.sample_rate, 2)
+}
+
+/// Multiplies every sample by `gain`
+pub fn apply_gain(audio: &mut AudioBuffer, gain: f32) {
+    if gain != 1.0 {
+        audio.samples.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+/// A biquad filter in direct form I
+pub struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    fn new(b: [f32; 3], a0: f32, a: [f32; 2]) -> Biquad {
+        Biquad {
+            b: b.map(|b| b / a0),
+            a: a.map(|a| a / a0),
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    /// Low pass from the Audio EQ Cookbook
+    pub fn low_pass(sample_rate: u32, frequency: f32, q: f32) -> Biquad {
+        let w0 = 2.0 * PI * frequency / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        Biquad::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            1.0 + alpha,
+            [-2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// High pass from the Audio EQ Cookbook
+    pub fn high_pass(sample_rate: u32, frequency: f32, q: f32) -> Biquad {
+        let w0 = 2.0 * PI * frequency / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        Biquad::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            1.0 + alpha,
+            [-2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// The two stage K-weighting filter from ITU-R BS.1770 for the given sample rate
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f32;
+
+    // high shelf modelling the acoustic effect of the head
+    let k = (PI * 1_681.974_5 / rate).tan();
+    let q = 0.707_175_25;
+    let vh = db_to_gain(3.999_843_8);
+    let vb = vh.powf(0.499_666_78);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    // high pass that ignores rumble below ~40 Hz
+    let k = (PI * 38.135_47 / rate).tan();
+    let q = 0.500_327;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+fn mean_square_to_lufs(mean_square: f32) -> f32 {
+    -0.691 + 10.0 * mean_square.max(f32::MIN_POSITIVE).log10()
+}
+
+/// Measures integrated loudness in LUFS following ITU-R BS.1770 with its gating.
+/// Audio can be added a piece at a time, the filters carry over between pieces.
+pub struct LoudnessMeter {
+    sample_rate: u32,
+    filters: Vec<[Biquad; 2]>,
+    /// Per frame energy after K-weighting, summed across channels
+    energy: Vec<f32>,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: u16) -> LoudnessMeter {
+        LoudnessMeter {
+            sample_rate,
+            filters: (0..channels.max(1))
+                .map(|_| k_weighting(sample_rate))
+                .collect(),
+            energy: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, audio: &AudioBuffer) {
+        let channels = self.filters.len();
+        for frame in audio.samples.chunks_exact(channels) {
+            let energy = frame
+                .iter()
+                .zip(&mut self.filters)
+                .map(|(sample, [shelf, high_pass])| {
+                    let weighted = high_pass.process(shelf.process(*sample));
+                    weighted * weighted
+                })
+                .sum();
+            self.energy.push(energy);
+        }
+    }
+
+    /// Loudness of everything added so far, None for silence
+    pub fn loudness(&self) -> Option<f32> {
+        let rate = self.sample_rate as f32;
+        let frames = self.energy.len();
+        if frames == 0 {
+            return None;
+        }
+
+        // short utterances are measured as a single block
+        let block = ((LOUDNESS_BLOCK_SECS * rate) as usize).clamp(1, frames);
+        let step = ((LOUDNESS_STEP_SECS * rate) as usize).max(1);
+        let blocks: Vec<f32> = (0..=frames - block)
+            .step_by(step)
+            .map(|start| self.energy[start..start + block].iter().sum::<f32>() / block as f32)
+            .filter(|mean_square| mean_square_to_lufs(*mean_square) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if blocks.is_empty() {
+            return None;
+        }
+        let ungated = blocks.iter().sum::<f32>() / blocks.len() as f32;
+        let relative_gate = mean_square_to_lufs(ungated) - RELATIVE_GATE_LU;
+        let gated: Vec<f32> = blocks
+            .into_iter()
+            .filter(|mean_square| mean_square_to_lufs(*mean_square) > relative_gate)
+            .collect();
+        if gated.is_empty() {
+            return Some(mean_square_to_lufs(ungated));
+        }
+        Some(mean_square_to_lufs(
+            gated.iter().sum::<f32>() / gated.len() as f32,
+        ))
+    }
+}
+
+/// Keeps peaks under the ceiling, reducing gain instantly and recovering over the release time.
+/// The gain carries over between pieces, so a peak at the end of one sentence
+/// is still released smoothly at the start of the next.
+pub struct Limiter {
+    ceiling: f32,
+    release_ms: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(ceiling_db: f32, release_ms: f32) -> Limiter {
+        Limiter {
+            ceiling: db_to_gain(ceiling_db),
+            release_ms: release_ms.max(1.0),
+            gain: 1.0,
+        }
+    }
+
+    pub fn process(&mut self, audio: &mut AudioBuffer) {
+        let channels = audio.channels.max(1) as usize;
+        let release_samples = (self.release_ms / 1000.0 * audio.sample_rate as f32).max(1.0);
+        let release = (-1.0 / release_samples).exp();
+        for frame in audio.samples.chunks_mut(channels) {
+            let peak = frame
+                .iter()
+                .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            let wanted = if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1.0
+            };
+            self.gain = if wanted < self.gain {
+                wanted
+            } else {
+                wanted + (self.gain - wanted) * release
+            };
+            frame.iter_mut().for_each(|sample| *sample *= self.gain);
+        }
+    }
+}
+
+/// Runs an utterance through the processing chain one sentence at a time: trimming and
+/// normalization, then the voice's pitch, effects, volume and pan, then the limiter so nothing clips.
+/// Trimming, loudness and the limiter look at the whole utterance rather than each sentence,
+/// so pauses between sentences are kept and quiet sentences aren't boosted on their own.
+pub struct Processor {
+    config: ProcessingConfig,
+    /// The voice's pitch runs first, followed by the other effects
+    effects: Vec<ActiveEffect>,
+    volume: f32,
+    /// Position in the stereo field, None leaves the audio as it is
+    pan: Option<f32>,
+    /// Sample rate and channels of the utterance, known once the first sentence arrives
+    format: Option<(u32, u16)>,
+    meter: Option<LoudnessMeter>,
+    limiter: Limiter,
+    /// Normalization gain, updated as more of the utterance is measured
+    gain: f32,
+    /// Whether anything above the silence threshold has been heard yet
+    started: bool,
+    /// Trailing silence held back until we know whether more speech follows
+    held_silence: Vec<f32>,
+}
+
+impl Processor {
+    pub fn new(
+        pitch_semitones: f32,
+        effects: Vec<Effect>,
+        volume: f32,
+        pan: Option<f32>,
+    ) -> Processor {
+        let config = PROCESSING.lock().unwrap().clone();
+        Processor::with_config(config, pitch_semitones, effects, volume, pan)
+    }
+
+    fn with_config(
+        config: ProcessingConfig,
+        pitch_semitones: f32,
+        effects: Vec<Effect>,
+        volume: f32,
+        pan: Option<f32>,
+    ) -> Processor {
+        let limiter = Limiter::new(config.limiter_ceiling_db, config.limiter_release_ms);
+        let pitch = Effect::PitchShift {
+            semitones: pitch_semitones,
+        };
+        Processor {
+            config,
+            effects: std::iter::once(pitch)
+                .chain(effects)
+                .map(ActiveEffect::new)
+                .collect(),
+            volume,
+            pan,
+            format: None,
+            meter: None,
+            limiter,
+            gain: 1.0,
+            started: false,
+            held_silence: Vec::new(),
+        }
+    }
+
+    /// Processes the next sentence of the utterance, which may come back empty
+    pub fn process(&mut self, audio: &AudioBuffer) -> AudioBuffer {
+        self.format = Some((audio.sample_rate, audio.channels));
+        let audio = if self.config.trim_silence {
+            self.trim(audio)
+        } else {
+            audio.clone()
+        };
+        self.run_chain(audio, false)
+    }
+
+    /// Processes the silence kept after the last sentence and the tails of echo and reverb.
+    /// Returns None when there is nothing more to play.
+    pub fn finish(&mut self) -> Option<AudioBuffer> {
+        let (sample_rate, channels) = self.format?;
+        let mut samples = std::mem::take(&mut self.held_silence);
+        samples.truncate(self.keep_frames(sample_rate) * channels.max(1) as usize);
+        let audio = self.run_chain(AudioBuffer::new(samples, sample_rate, channels), true);
+        if audio.samples.is_empty() {
+            return None;
+        }
+        Some(audio)
+    }
+
+    fn keep_frames(&self, sample_rate: u32) -> usize {
+        (Duration::from_millis(self.config.keep_silence_ms).as_secs_f32() * sample_rate as f32)
+            as usize
+    }
+
+    /// Cuts the silence before the first sentence and holds back the silence after each one,
+    /// since only the end of the last sentence is the end of the utterance
+    fn trim(&mut self, audio: &AudioBuffer) -> AudioBuffer {
+        let channels = audio.channels.max(1) as usize;
+        let threshold = db_to_gain(self.config.silence_threshold_db);
+        let loud = |frame: &[f32]| frame.iter().any(|sample| sample.abs() > threshold);
+        let frames: Vec<&[f32]> = audio.samples.chunks(channels).collect();
+        let Some(first) = frames.iter().position(|frame| loud(frame)) else {
+            // all silence, a pause if more speech follows
+            if self.started {
+                self.held_silence.extend_from_slice(&audio.samples);
+            }
+            return AudioBuffer::new(Vec::new(), audio.sample_rate, audio.channels);
+        };
+        let last = frames
+            .iter()
+            .rposition(|frame| loud(frame))
+            .unwrap_or(first);
+
+        let start = if self.started {
+            0
+        } else {
+            first.saturating_sub(self.keep_frames(audio.sample_rate))
+        };
+        self.started = true;
+        let mut samples = std::mem::take(&mut self.held_silence);
+        samples.extend_from_slice(&audio.samples[start * channels..(last + 1) * channels]);
+        self.held_silence = audio.samples[(last + 1) * channels..].to_vec();
+        AudioBuffer::new(samples, audio.sample_rate, audio.channels)
+    }
+
+    /// The last piece of the utterance also gets the effects' tails
+    fn run_chain(&mut self, mut audio: AudioBuffer, last: bool) -> AudioBuffer {
+        if audio.samples.is_empty() && !last {
+            return audio;
+        }
+        if self.config.normalize && !audio.samples.is_empty() {
+            let meter = self
+                .meter
+                .get_or_insert_with(|| LoudnessMeter::new(audio.sample_rate, audio.channels));
+            meter.add(&audio);
+            if let Some(loudness) = meter.loudness() {
+                let gain_db = (self.config.target_lufs - loudness).min(self.config.max_gain_db);
+                self.gain = db_to_gain(gain_db);
+            }
+            apply_gain(&mut audio, self.gain);
+        }
+        for effect in &mut self.effects {
+            audio = effect.process(&audio);
+            if last {
+                if let Some(tail) = effect.tail(audio.sample_rate, audio.channels) {
+                    audio.samples.extend(tail.samples);
+                }
+            }
+        }
+        apply_gain(&mut audio, self.volume);
+        if let Some(position) = self.pan {
+            audio = pan(&audio, position);
+        }
+        if self.config.limiter {
+            self.limiter.process(&mut audio);
+        }
+        audio
+    }
+}
+
+/// Runs a whole utterance through the processing chain in one go
+pub fn process(
+    audio: &AudioBuffer,
+    pitch_semitones: f32,
+    effects: &[Effect],
+    volume: f32,
+    pan: Option<f32>,
+) -> AudioBuffer {
+    let mut processor = Processor::new(pitch_semitones, effects.to_vec(), volume, pan);
+    let mut output = processor.process(audio);
+    if let Some(tail) = processor.finish() {
+        output.samples.extend(tail.samples);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn sine(frequency: f32, amplitude: f32, secs: f32) -> AudioBuffer {
+        let samples = (0..(secs * RATE as f32) as usize)
+            .map(|i| amplitude * (2.0 * PI * frequency * i as f32 / RATE as f32).sin())
+            .collect();
+        AudioBuffer::new(samples, RATE, 1)
+    }
+
+    fn silence(secs: f32) -> AudioBuffer {
+        AudioBuffer::new(vec![0.0; (secs * RATE as f32) as usize], RATE, 1)
+    }
+
+    #[test]
+    fn measures_a_full_scale_sine() {
+        // BS.1770 puts a full scale 997 Hz sine at -3.01 LUFS
+        let mut meter = LoudnessMeter::new(RATE, 1);
+        meter.add(&sine(997.0, 1.0, 3.0));
+        let loudness = meter.loudness().unwrap();
+        assert!((loudness + 3.01).abs() < 0.1, "{}", loudness);
+    }
+
+    #[test]
+    fn measures_the_same_in_pieces() {
+        let audio = sine(440.0, 0.3, 2.0);
+        let mut whole = LoudnessMeter::new(RATE, 1);
+        whole.add(&audio);
+        let mut pieces = LoudnessMeter::new(RATE, 1);
+        for piece in audio.samples.chunks(7000) {
+            pieces.add(&AudioBuffer::new(piece.to_vec(), RATE, 1));
+        }
+        assert_eq!(whole.loudness(), pieces.loudness());
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = LoudnessMeter::new(RATE, 1);
+        assert_eq!(meter.loudness(), None);
+        meter.add(&silence(1.0));
+        assert_eq!(meter.loudness(), None);
+    }
+
+    #[test]
+    fn limiter_keeps_peaks_under_the_ceiling() {
+        let mut limiter = Limiter::new(-1.0, 50.0);
+        let mut audio = sine(440.0, 2.0, 0.5);
+        limiter.process(&mut audio);
+        let ceiling = db_to_gain(-1.0);
+        assert!(audio
+            .samples
+            .iter()
+            .all(|sample| sample.abs() <= ceiling + 1e-6));
+    }
+
+    #[test]
+    fn limiter_releases_across_pieces() {
+        let mut limiter = Limiter::new(0.0, 50.0);
+        let mut loud = AudioBuffer::new(vec![2.0; 100], RATE, 1);
+        limiter.process(&mut loud);
+        let mut quiet = AudioBuffer::new(vec![0.5; 100], RATE, 1);
+        limiter.process(&mut quiet);
+        // still recovering from the peak in the previous piece
+        assert!(quiet.samples[0] < 0.3);
+        assert!(quiet.samples[99] > quiet.samples[0]);
+    }
+
+    #[test]
+    fn centre_pan_matches_mono() {
+        let audio = AudioBuffer::new(vec![0.5; 4], RATE, 1);
+        let centre = pan(&audio, 0.0);
+        assert_eq!(centre.channels, 2);
+        assert!(centre
+            .samples
+            .iter()
+            .all(|sample| (sample - 0.5).abs() < 1e-6));
+        // the power stays the same wherever the audio is placed
+        let left = pan(&audio, -1.0);
+        let power = |audio: &AudioBuffer| audio.samples.iter().map(|s| s * s).sum::<f32>();
+        assert!((power(&left) - power(&centre)).abs() < 1e-5);
+        assert!(left.samples[1].abs() < 1e-6);
+    }
+
+    fn trim_only() -> ProcessingConfig {
+        ProcessingConfig {
+            normalize: false,
+            limiter: false,
+            ..ProcessingConfig::default()
+        }
+    }
+
+    #[test]
+    fn trims_only_the_ends_of_the_utterance() {
+        let mut processor = Processor::with_config(trim_only(), 0.0, Vec::new(), 1.0, None);
+        let keep = (0.08 * RATE as f32) as usize;
+        let mut first = silence(0.5);
+        first.samples.extend(sine(440.0, 0.5, 0.2).samples);
+        first.samples.extend(silence(0.3).samples);
+        let mut second = silence(0.2);
+        second.samples.extend(sine(440.0, 0.5, 0.2).samples);
+        second.samples.extend(silence(0.5).samples);
+
+        let mut output = processor.process(&first).samples;
+        output.extend(processor.process(&second).samples);
+        output.extend(processor.finish().unwrap().samples);
+        let speech = 2 * (0.2 * RATE as f32) as usize;
+        let pause = (0.5 * RATE as f32) as usize;
+        // the pause between the sentences is kept whole, the ends are trimmed
+        assert!(output.len() <= speech + pause + 2 * keep);
+        assert!(output.len() >= speech + pause + keep);
+    }
+
+    #[test]
+    fn normalizes_the_whole_utterance() {
+        let config = ProcessingConfig {
+            trim_silence: false,
+            limiter: false,
+            ..ProcessingConfig::default()
+        };
+        let mut processor = Processor::with_config(config, 0.0, Vec::new(), 1.0, None);
+        let loud = processor.process(&sine(440.0, 0.5, 2.0));
+        let quiet = processor.process(&sine(440.0, 0.05, 0.2));
+        let peak = |audio: &AudioBuffer| audio.samples.iter().fold(0.0f32, |a, b| a.max(b.abs()));
+        // the short quiet sentence isn't boosted up to the level of the loud one
+        assert!(peak(&quiet) < peak(&loud) / 2.0);
+    }
+}
@@ -0,0 +1,501 @@
+use crate::audio::AudioBuffer;
+use crate::chat::MessageKind;
+use crate::dsp::{self, Biquad, PitchShifter};
+use crate::queue::QueueItem;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::Mutex;
+
+/// Longest tail echo and reverb may add after the speech
+const MAX_TAIL_SECS: f32 = 3.0;
+const REVERB_TAIL_SECS: f32 = 1.5;
+/// Comb and allpass delays of the reverb in samples at 44.1 kHz, from Freeverb
+const REVERB_COMB_DELAYS: [usize; 4] = [1557, 1617, 1491, 1422];
+const REVERB_ALLPASS_DELAYS: [usize; 2] = [556, 225];
+
+/// A single step of a voice effects chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Effect {
+    PitchShift {
+        semitones: f32,
+    },
+    Echo {
+        delay_ms: f32,
+        /// How much of each repeat feeds the next one, below 1.0
+        feedback: f32,
+        mix: f32,
+    },
+    Reverb {
+        /// Longer tails towards 1.0
+        room_size: f32,
+        /// How quickly high frequencies die away, 0.0 to 1.0
+        damping: f32,
+        mix: f32,
+    },
+    /// Reduces the bit depth and sample rate for a lo-fi sound
+    Bitcrush {
+        bits: u32,
+        /// Holds every sample for this many samples
+        downsample: usize,
+    },
+    /// Ring modulation, the classic sci-fi robot voice
+    Robot {
+        frequency_hz: f32,
+    },
+    /// Band limited and slightly overdriven, like a radio or telephone
+    Radio {
+        low_hz: f32,
+        high_hz: f32,
+        drive: f32,
+    },
+}
+
+/// State an effect keeps for one channel between the sentences of an utterance
+enum ChannelState {
+    None,
+    /// The last `delay` samples of the echo's output
+    Echo(Vec<f32>),
+    Reverb(Reverb),
+    /// The sample being held by the bitcrusher
+    Bitcrush(f32),
+    Radio(Biquad, Biquad),
+    PitchShift(PitchShifter),
+}
+
+/// An effect partway through an utterance. Delay lines, filters, grains and the robot's
+/// phase carry over from one sentence to the next so there are no clicks or
+/// restarted echoes at sentence boundaries, and tails are only added at the end.
+pub struct ActiveEffect {
+    effect: Effect,
+    channels: Vec<ChannelState>,
+    /// Frames processed so far
+    position: usize,
+}
+
+impl ActiveEffect {
+    pub fn new(effect: Effect) -> ActiveEffect {
+        ActiveEffect {
+            effect,
+            channels: Vec::new(),
+            position: 0,
+        }
+    }
+
+    fn new_state(&self, sample_rate: u32) -> ChannelState {
+        match self.effect {
+            Effect::Echo { delay_ms, .. } => {
+                ChannelState::Echo(vec![0.0; echo_delay(delay_ms, sample_rate)])
+            }
+            Effect::Reverb {
+                room_size, damping, ..
+            } => ChannelState::Reverb(Reverb::new(sample_rate, room_size, damping)),
+            Effect::Bitcrush { .. } => ChannelState::Bitcrush(0.0),
+            Effect::Radio {
+                low_hz, high_hz, ..
+            } => ChannelState::Radio(
+                Biquad::high_pass(sample_rate, low_hz, 0.707),
+                Biquad::low_pass(sample_rate, high_hz, 0.707),
+            ),
+            Effect::PitchShift { semitones } => {
+                ChannelState::PitchShift(PitchShifter::new(semitones, sample_rate))
+            }
+            Effect::Robot { .. } => ChannelState::None,
+        }
+    }
+
+    /// Runs the next sentence of the utterance through the effect
+    pub fn process(&mut self, audio: &AudioBuffer) -> AudioBuffer {
+        let rate = audio.sample_rate;
+        let channels = audio.channels.max(1) as usize;
+        while self.channels.len() < channels {
+            let state = self.new_state(rate);
+            self.channels.push(state);
+        }
+        if let Effect::PitchShift { .. } = self.effect {
+            return self.shift_pitch(audio, |shifter, channel| shifter.process(channel));
+        }
+
+        let mut output = audio.clone();
+        for (frame, samples) in output.samples.chunks_mut(channels).enumerate() {
+            let position = self.position + frame;
+            for (sample, state) in samples.iter_mut().zip(&mut self.channels) {
+                *sample = match (&self.effect, state) {
+                    (&Effect::Echo { feedback, mix, .. }, ChannelState::Echo(history)) => {
+                        let index = position % history.len();
+                        let wet = *sample + feedback.clamp(0.0, 0.95) * history[index];
+                        history[index] = wet;
+                        mix_dry_wet(*sample, wet, mix)
+                    }
+                    (&Effect::Reverb { mix, .. }, ChannelState::Reverb(reverb)) => {
+                        let wet = reverb.process(*sample, position);
+                        mix_dry_wet(*sample, wet, mix)
+                    }
+                    (&Effect::Bitcrush { bits, downsample }, ChannelState::Bitcrush(held)) => {
+                        let levels = 2f32.powi(bits.clamp(1, 16) as i32 - 1);
+                        if position.is_multiple_of(downsample.max(1)) {
+                            *held = (*sample * levels).round() / levels;
+                        }
+                        *held
+                    }
+                    (&Effect::Robot { frequency_hz }, _) => {
+                        *sample * (2.0 * PI * frequency_hz * position as f32 / rate as f32).sin()
+                    }
+                    (&Effect::Radio { drive, .. }, ChannelState::Radio(high_pass, low_pass)) => {
+                        let drive = drive.max(1.0);
+                        let filtered = low_pass.process(high_pass.process(*sample));
+                        (filtered * drive).tanh() / drive.tanh()
+                    }
+                    _ => *sample,
+                };
+            }
+        }
+        self.position += output.samples.len() / channels;
+        output
+    }
+
+    /// Runs each channel through its pitch shifter, they all hold back the same amount
+    fn shift_pitch(
+        &mut self,
+        audio: &AudioBuffer,
+        shift: impl Fn(&mut PitchShifter, &[f32]) -> Vec<f32>,
+    ) -> AudioBuffer {
+        let input = dsp::deinterleave(audio);
+        let shifted: Vec<Vec<f32>> = self
+            .channels
+            .iter_mut()
+            .zip(&input)
+            .map(|(state, channel)| match state {
+                ChannelState::PitchShift(shifter) => shift(shifter, channel),
+                _ => channel.clone(),
+            })
+            .collect();
+        AudioBuffer::new(dsp::interleave(&shifted), audio.sample_rate, audio.channels)
+    }
+
+    /// Lets echo and reverb ring out and the pitch shifter catch up after the last sentence.
+    /// Returns None for effects that stop with the speech.
+    pub fn tail(&mut self, sample_rate: u32, channels: u16) -> Option<AudioBuffer> {
+        let frames = match self.effect {
+            Effect::PitchShift { .. } => {
+                let empty = AudioBuffer::new(Vec::new(), sample_rate, channels);
+                let tail = self.shift_pitch(&empty, |shifter, _| shifter.finish());
+                return Some(tail).filter(|tail| !tail.samples.is_empty());
+            }
+            Effect::Echo {
+                delay_ms, feedback, ..
+            } => {
+                let feedback = feedback.clamp(0.0, 0.95);
+                // keep repeating until the echo has died down to 1%
+                let repeats = if feedback > 0.0 {
+                    (0.01f32.ln() / feedback.ln()).ceil() as usize
+                } else {
+                    1
+                };
+                (echo_delay(delay_ms, sample_rate) * repeats)
+                    .min((MAX_TAIL_SECS * sample_rate as f32) as usize)
+            }
+            Effect::Reverb { .. } => (REVERB_TAIL_SECS * sample_rate as f32) as usize,
+            _ => return None,
+        };
+        let silence = vec![0.0; frames * channels.max(1) as usize];
+        Some(self.process(&AudioBuffer::new(silence, sample_rate, channels)))
+    }
+}
+
+fn echo_delay(delay_ms: f32, sample_rate: u32) -> usize {
+    ((delay_ms / 1000.0 * sample_rate as f32) as usize).max(1)
+}
+
+fn mix_dry_wet(dry: f32, wet: f32, mix: f32) -> f32 {
+    let mix = mix.clamp(0.0, 1.0);
+    dry * (1.0 - mix) + wet * mix
+}
+
+/// A small Schroeder reverb: parallel damped combs followed by allpasses
+struct Reverb {
+    /// Delay line and damping filter state of each comb
+    combs: Vec<(Vec<f32>, f32)>,
+    allpasses: Vec<Vec<f32>>,
+    feedback: f32,
+    damping: f32,
+}
+
+impl Reverb {
+    fn new(sample_rate: u32, room_size: f32, damping: f32) -> Reverb {
+        let scale = |delay: usize| ((delay * sample_rate as usize) / 44100).max(1);
+        Reverb {
+            combs: REVERB_COMB_DELAYS
+                .map(|delay| (vec![0.0; scale(delay)], 0.0))
+                .into(),
+            allpasses: REVERB_ALLPASS_DELAYS
+                .map(|delay| vec![0.0; scale(delay)])
+                .into(),
+            feedback: 0.7 + 0.28 * room_size.clamp(0.0, 1.0),
+            damping: damping.clamp(0.0, 1.0),
+        }
+    }
+
+    fn process(&mut self, input: f32, position: usize) -> f32 {
+        let mut output = 0.0;
+        for (buffer, filtered) in &mut self.combs {
+            let index = position % buffer.len();
+            let delayed = buffer[index];
+            *filtered = delayed * (1.0 - self.damping) + *filtered * self.damping;
+            buffer[index] = input + *filtered * self.feedback;
+            output += delayed / REVERB_COMB_DELAYS.len() as f32;
+        }
+        for buffer in &mut self.allpasses {
+            let index = position % buffer.len();
+            let delayed = buffer[index];
+            buffer[index] = output + delayed * 0.5;
+            output = delayed - output;
+        }
+        output
+    }
+}
+
+/// Messages cheering at least `min_bits` get the preset
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BitsEffect {
+    pub min_bits: u32,
+    pub preset: String,
+}
+
+/// Named effect chains and when to use them. The most specific match wins:
+/// redemption, then bits, then the user, then the voice.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EffectsConfig {
+    pub enabled: bool,
+    pub presets: HashMap<String, Vec<Effect>>,
+    /// Speaker id to preset name
+    pub voice_presets: HashMap<i64, String>,
+    /// Lowercase username to preset name
+    pub user_presets: HashMap<String, String>,
+    pub bits_presets: Vec<BitsEffect>,
+    /// Channel point reward id to preset name
+    pub redemption_presets: HashMap<String, String>,
+}
+
+impl Default for EffectsConfig {
+    fn default() -> Self {
+        let presets = [
+            (
+                "robot",
+                vec![
+                    Effect::Robot { frequency_hz: 50.0 },
+                    Effect::Bitcrush {
+                        bits: 8,
+                        downsample: 2,
+                    },
+                ],
+            ),
+            (
+                "echo",
+                vec![Effect::Echo {
+                    delay_ms: 250.0,
+                    feedback: 0.4,
+                    mix: 0.5,
+                }],
+            ),
+            (
+                "reverb",
+                vec![Effect::Reverb {
+                    room_size: 0.8,
+                    damping: 0.4,
+                    mix: 0.35,
+                }],
+            ),
+            (
+                "radio",
+                vec![Effect::Radio {
+                    low_hz: 400.0,
+                    high_hz: 3000.0,
+                    drive: 3.0,
+                }],
+            ),
+            ("chipmunk", vec![Effect::PitchShift { semitones: 7.0 }]),
+        ];
+        EffectsConfig {
+            enabled: true,
+            presets: presets
+                .into_iter()
+                .map(|(name, effects)| (name.to_string(), effects))
+                .collect(),
+            voice_presets: HashMap::new(),
+            user_presets: HashMap::new(),
+            bits_presets: Vec::new(),
+            redemption_presets: HashMap::new(),
+        }
+    }
+}
+
+impl EffectsConfig {
+    /// Picks the preset for a message spoken with the given speaker
+    fn preset_for(&self, item: &QueueItem, speaker: i64) -> Option<&String> {
+        let redemption = item
+            .reward_id
+            .as_ref()
+            .filter(|_| item.kind == MessageKind::Redemption)
+            .and_then(|reward_id| self.redemption_presets.get(reward_id));
+        let bits = self
+            .bits_presets
+            .iter()
+            .filter(|bits| item.bits > 0 && item.bits >= bits.min_bits)
+            .max_by_key(|bits| bits.min_bits)
+            .map(|bits| &bits.preset);
+        redemption
+            .or(bits)
+            .or_else(|| self.user_presets.get(&item.user.to_lowercase()))
+            .or_else(|| self.voice_presets.get(&speaker))
+    }
+}
+
+lazy_static! {
+    static ref EFFECTS: Mutex<EffectsConfig> = Mutex::new(EffectsConfig::default());
+}
+
+pub fn set_effects_config(config: EffectsConfig) {
+    *EFFECTS.lock().unwrap() = config;
+}
+
+/// The effects chain to run on a message, empty when none applies
+pub fn effects_for(item: &QueueItem, speaker: i64) -> Vec<Effect> {
+    let preset = {
+        let config = EFFECTS.lock().unwrap();
+        if !config.enabled {
+            return Vec::new();
+        }
+        match config.preset_for(item, speaker) {
+            Some(preset) => preset.clone(),
+            None => return Vec::new(),
+        }
+    };
+    preset_effects(Some(&preset), speaker).unwrap_or_else(|e| {
+        println!("{}", e);
+        Vec::new()
+    })
+}
+
+/// The effects of a named preset, or of the voice's preset when no name is given
+pub fn preset_effects(preset: Option<&str>, speaker: i64) -> Result<Vec<Effect>, String> {
+    let config = EFFECTS.lock().unwrap();
+    let preset = match preset {
+        Some(preset) => preset,
+        None if !config.enabled => return Ok(Vec::new()),
+        None => match config.voice_presets.get(&speaker) {
+            Some(preset) => preset.as_str(),
+            None => return Ok(Vec::new()),
+        },
+    };
+    config
+        .presets
+        .get(preset)
+        .cloned()
+        .ok_or_else(|| format!("Unknown effect preset {}", preset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 22050;
+
+    fn speech() -> AudioBuffer {
+        let samples = (0..RATE as usize)
+            .map(|i| (i as f32 * 0.05).sin() * 0.5)
+            .collect();
+        AudioBuffer::new(samples, RATE, 1)
+    }
+
+    #[test]
+    fn sentences_sound_the_same_as_one_piece() {
+        let effects = [
+            Effect::Echo {
+                delay_ms: 100.0,
+                feedback: 0.5,
+                mix: 0.5,
+            },
+            Effect::Reverb {
+                room_size: 0.8,
+                damping: 0.4,
+                mix: 0.35,
+            },
+            Effect::Bitcrush {
+                bits: 8,
+                downsample: 3,
+            },
+            Effect::Robot { frequency_hz: 50.0 },
+            Effect::Radio {
+                low_hz: 400.0,
+                high_hz: 3000.0,
+                drive: 3.0,
+            },
+            Effect::PitchShift { semitones: 4.0 },
+            Effect::PitchShift { semitones: -5.0 },
+        ];
+        let audio = speech();
+        for effect in effects {
+            let whole = ActiveEffect::new(effect.clone()).process(&audio);
+            let mut active = ActiveEffect::new(effect.clone());
+            let mut pieces = Vec::new();
+            for piece in audio.samples.chunks(5001) {
+                pieces.extend(
+                    active
+                        .process(&AudioBuffer::new(piece.to_vec(), RATE, 1))
+                        .samples,
+                );
+            }
+            assert_eq!(whole.samples, pieces, "{:?}", effect);
+        }
+    }
+
+    #[test]
+    fn echo_rings_out_and_robot_stops() {
+        let echo = Effect::Echo {
+            delay_ms: 100.0,
+            feedback: 0.5,
+            mix: 0.5,
+        };
+        let mut active = ActiveEffect::new(echo);
+        let processed = active.process(&speech());
+        assert_eq!(processed.samples.len(), speech().samples.len());
+        let tail = active.tail(RATE, 1).unwrap();
+        assert!(tail.samples.iter().any(|sample| sample.abs() > 0.01));
+
+        let mut robot = ActiveEffect::new(Effect::Robot { frequency_hz: 50.0 });
+        robot.process(&speech());
+        assert!(robot.tail(RATE, 1).is_none());
+    }
+
+    #[test]
+    fn pitch_shift_keeps_the_length() {
+        let stereo = AudioBuffer::new(
+            speech()
+                .samples
+                .iter()
+                .flat_map(|&sample| [sample, -sample])
+                .collect(),
+            RATE,
+            2,
+        );
+        let mut active = ActiveEffect::new(Effect::PitchShift { semitones: 3.0 });
+        let mut shifted = Vec::new();
+        for piece in stereo.samples.chunks(4000) {
+            shifted.extend(
+                active
+                    .process(&AudioBuffer::new(piece.to_vec(), RATE, 2))
+                    .samples,
+            );
+        }
+        shifted.extend(active.tail(RATE, 2).unwrap().samples);
+        assert_eq!(shifted.len(), stereo.samples.len());
+        for frame in shifted.chunks(2) {
+            assert_eq!(frame[0], -frame[1]);
+        }
+        assert!(shifted.iter().any(|sample| sample.abs() > 0.1));
+    }
+}
@@ -0,0 +1,336 @@
+use crate::audio::AudioBuffer;
+use crate::dsp;
+use crate::effects;
+use crate::tts::{self, SpeechConfig, SynthesisParams};
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use piper_rs::synth::PiperSpeechSynthesizer;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Opus only runs at a few fixed rates, OGG exports are resampled to this one
+const OPUS_SAMPLE_RATE: u32 = 48000;
+/// Samples per channel in each Opus packet, 20ms at 48kHz
+const OPUS_FRAME: usize = 960;
+const OPUS_BITRATE_PER_CHANNEL: i32 = 64000;
+/// The largest packet Opus recommends making room for
+const OPUS_MAX_PACKET: usize = 4000;
+
+const CLI_USAGE: &str = "usage: twitch-tools export --text <text> --out <file.wav|file.ogg> \
+[--speaker <id>] [--length-scale <value>] [--noise-scale <value>] [--noise-w <value>] \
+[--pitch <semitones>] [--pan <-1.0 to 1.0>] [--preset <effect preset>] [--resources <dir>]";
+
+/// Text and synthesis settings for a file export.
+/// Anything left unset uses the saved speech settings, like live playback does.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ExportRequest {
+    pub text: String,
+    /// The selected voice when unset
+    pub speaker: Option<i64>,
+    pub length_scale: Option<f32>,
+    pub noise_scale: Option<f32>,
+    pub noise_w: Option<f32>,
+    pub pitch_semitones: Option<f32>,
+    /// Stereo position, -1.0 is hard left and 1.0 hard right
+    pub pan: Option<f32>,
+    /// Effects preset to apply, the voice's preset when unset
+    pub preset: Option<String>,
+}
+
+/// Synthesizes the request with a model of its own, so exports don't hold up live playback
+pub fn synthesize(
+    resources_dir: &Path,
+    request: &ExportRequest,
+    speech: &SpeechConfig,
+    default_speaker: i64,
+) -> Result<AudioBuffer, String> {
+    if request.text.trim().is_empty() {
+        return Err("Nothing to export, the text is empty".to_string());
+    }
+    env::set_var(
+        "PIPER_ESPEAKNG_DATA_DIRECTORY",
+        resources_dir.to_string_lossy().to_string(),
+    );
+    let config_path = resources_dir.join("model.onnx.json");
+    let model = piper_rs::from_config_path(&config_path)
+        .map_err(|e| format!("Failed to load model: {}", e))?;
+
+    let speaker = request.speaker.unwrap_or(default_speaker);
+    model.set_speaker(speaker);
+    let defaults = tts::noise_defaults(&model)?;
+    tts::set_synthesis_params(
+        &model,
+        SynthesisParams {
+            length_scale: request.length_scale.unwrap_or(speech.length_scale),
+            noise_scale: request.noise_scale.or(speech.noise_scale),
+            noise_w: request.noise_w.or(speech.noise_w),
+        },
+        defaults,
+    )?;
+    let (sample_rate, channels) = tts::output_format(&model);
+
+    let synth = PiperSpeechSynthesizer::new(model).map_err(|e| e.to_string())?;
+    let chunks = synth
+        .synthesize_parallel(request.text.clone(), None)
+        .map_err(|e| format!("Error synthesizing speech: {}", e))?;
+    let mut samples = Vec::new();
+    for chunk in chunks {
+        samples.append(&mut chunk.map_err(|e| e.to_string())?.into_vec());
+    }
+
+    // the same processing live messages get, in one go rather than per sentence
+    let effects = effects::preset_effects(request.preset.as_deref(), speaker)?;
+    Ok(dsp::process(
+        &AudioBuffer::new(samples, sample_rate, channels),
+        request.pitch_semitones.unwrap_or(speech.pitch_semitones),
+        &effects,
+        speech.voice_volume(speaker),
+        request.pan.or_else(|| speech.pan_for("", speaker)),
+    ))
+}
+
+/// Writes 16 bit PCM, which every editor and streaming tool can open
+pub fn write_wav(audio: &AudioBuffer, path: &Path) -> Result<(), String> {
+    let spec = hound::WavSpec {
+        channels: audio.channels,
+        sample_rate: audio.sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    for sample in &audio.samples {
+        writer
+            .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    writer
+        .finalize()
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Writes Opus in an OGG container at 48kHz, much smaller than WAV for sharing
+pub fn write_ogg(audio: &AudioBuffer, path: &Path) -> Result<(), String> {
+    let channels = match audio.channels {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        count => {
+            return Err(format!(
+                "OGG export supports 1 or 2 channels, not {}",
+                count
+            ))
+        }
+    };
+    let mut encoder = Encoder::new(SampleRate::Hz48000, channels, Application::Audio)
+        .map_err(|e| format!("Failed to create the Opus encoder: {}", e))?;
+    encoder
+        .set_bitrate(Bitrate::BitsPerSecond(
+            OPUS_BITRATE_PER_CHANNEL * audio.channels as i32,
+        ))
+        .map_err(|e| e.to_string())?;
+    // the decoder drops this many samples from the start, the encoder's delay
+    let pre_skip = encoder.lookahead().map_err(|e| e.to_string())? as usize;
+
+    let frames = audio.samples.len() / audio.channels as usize;
+    let len = (frames as u64 * OPUS_SAMPLE_RATE as u64 / audio.sample_rate.max(1) as u64) as usize;
+    let resampled: Vec<Vec<f32>> = dsp::deinterleave(audio)
+        .iter()
+        .map(|channel| dsp::resample_to_len(channel, len))
+        .collect();
+    let mut samples = dsp::interleave(&resampled);
+    // pad the end so the last packet is whole and holds the encoder's delay
+    let packets = (pre_skip + len).div_ceil(OPUS_FRAME).max(1);
+    samples.resize(packets * OPUS_FRAME * audio.channels as usize, 0.0);
+
+    let write_error = |e: std::io::Error| format!("Failed to write {}: {}", path.display(), e);
+    let file =
+        File::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let mut writer = PacketWriter::new(BufWriter::new(file));
+    let serial = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let head = opus_head(audio.channels as u8, pre_skip as u16, audio.sample_rate);
+    writer
+        .write_packet(head.into(), serial, PacketWriteEndInfo::EndPage, 0)
+        .map_err(write_error)?;
+    writer
+        .write_packet(opus_tags().into(), serial, PacketWriteEndInfo::EndPage, 0)
+        .map_err(write_error)?;
+
+    let mut packet = vec![0; OPUS_MAX_PACKET];
+    for (index, frame) in samples
+        .chunks(OPUS_FRAME * audio.channels as usize)
+        .enumerate()
+    {
+        let size = encoder
+            .encode_float(frame, &mut packet)
+            .map_err(|e| format!("Failed to encode {}: {}", path.display(), e))?;
+        // the last granule position tells the decoder where the audio really ends
+        let end = if index + 1 == packets {
+            PacketWriteEndInfo::EndStream
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let granule = ((index + 1) * OPUS_FRAME).min(pre_skip + len) as u64;
+        writer
+            .write_packet(packet[..size].into(), serial, end, granule)
+            .map_err(write_error)?;
+    }
+    writer.into_inner().flush().map_err(write_error)
+}
+
+/// The identification header every Ogg Opus stream starts with, from RFC 7845
+fn opus_head(channels: u8, pre_skip: u16, input_sample_rate: u32) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(channels);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&input_sample_rate.to_le_bytes());
+    // output gain
+    head.extend_from_slice(&0i16.to_le_bytes());
+    // channel mapping family 0, mono or stereo
+    head.push(0);
+    head
+}
+
+/// The comment header, naming the app and nothing else
+fn opus_tags() -> Vec<u8> {
+    let vendor = b"twitch-tools";
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    tags
+}
+
+#[derive(Debug, PartialEq)]
+enum ExportFormat {
+    Wav,
+    Ogg,
+}
+
+/// Picks the format from the file extension
+fn check_format(path: &Path) -> Result<ExportFormat, String> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("wav") => Ok(ExportFormat::Wav),
+        Some("ogg") => Ok(ExportFormat::Ogg),
+        _ => Err(format!("Unsupported export format {}", path.display())),
+    }
+}
+
+/// Synthesizes the request and saves it, returning how long the audio is
+pub fn export_to_file(
+    resources_dir: &Path,
+    request: &ExportRequest,
+    speech: &SpeechConfig,
+    default_speaker: i64,
+    path: &Path,
+) -> Result<Duration, String> {
+    // fail on the format before spending time on synthesis
+    let format = check_format(path)?;
+    let audio = synthesize(resources_dir, request, speech, default_speaker)?;
+    match format {
+        ExportFormat::Wav => write_wav(&audio, path)?,
+        ExportFormat::Ogg => write_ogg(&audio, path)?,
+    }
+    println!(
+        "Exported {:.1}s of speech to {}",
+        audio.duration().as_secs_f32(),
+        path.display()
+    );
+    Ok(audio.duration())
+}
+
+/// The resources bundled next to the executable
+fn default_resources_dir() -> Result<PathBuf, String> {
+    let exe = env::current_exe().map_err(|e| format!("Failed to find the executable: {}", e))?;
+    let dir = exe
+        .parent()
+        .ok_or_else(|| "Failed to find the executable's directory".to_string())?;
+    Ok(dir.join("resources"))
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+/// `twitch-tools export ...`, exports speech without opening the app.
+/// Settings that aren't given use the defaults, not the app's saved settings.
+pub fn run_cli(args: &[String]) -> Result<(), String> {
+    let mut request = ExportRequest::default();
+    let mut out: Option<PathBuf> = None;
+    let mut resources_dir: Option<PathBuf> = None;
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--text" => request.text = parse_value(flag, args.next())?,
+            "--out" => out = Some(parse_value(flag, args.next())?),
+            "--speaker" => request.speaker = Some(parse_value(flag, args.next())?),
+            "--length-scale" => request.length_scale = Some(parse_value(flag, args.next())?),
+            "--noise-scale" => request.noise_scale = Some(parse_value(flag, args.next())?),
+            "--noise-w" => request.noise_w = Some(parse_value(flag, args.next())?),
+            "--pitch" => request.pitch_semitones = Some(parse_value(flag, args.next())?),
+            "--pan" => request.pan = Some(parse_value(flag, args.next())?),
+            "--preset" => request.preset = Some(parse_value(flag, args.next())?),
+            "--resources" => resources_dir = Some(parse_value(flag, args.next())?),
+            "--help" | "-h" => {
+                println!("{}", CLI_USAGE);
+                return Ok(());
+            }
+            _ => return Err(format!("Unknown option {}\n{}", flag, CLI_USAGE)),
+        }
+    }
+    let out = out.ok_or_else(|| CLI_USAGE.to_string())?;
+    let resources_dir = match resources_dir {
+        Some(dir) => dir,
+        None => default_resources_dir()?,
+    };
+    export_to_file(&resources_dir, &request, &SpeechConfig::default(), 0, &out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_format_from_the_extension() {
+        assert_eq!(check_format(Path::new("intro.wav")), Ok(ExportFormat::Wav));
+        assert_eq!(check_format(Path::new("intro.OGG")), Ok(ExportFormat::Ogg));
+        assert!(check_format(Path::new("intro.mp3")).is_err());
+        assert!(check_format(Path::new("intro")).is_err());
+    }
+
+    #[test]
+    fn writes_the_opus_head() {
+        let head = opus_head(2, 312, 22050);
+        assert_eq!(head.len(), 19);
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[8..10], [1, 2]);
+        assert_eq!(u16::from_le_bytes([head[10], head[11]]), 312);
+        assert_eq!(
+            u32::from_le_bytes([head[12], head[13], head[14], head[15]]),
+            22050
+        );
+        assert_eq!(head[16..], [0, 0, 0]);
+    }
+
+    #[test]
+    fn help_is_not_an_error() {
+        assert_eq!(run_cli(&["--help".to_string()]), Ok(()));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::control::PLAYBACK;
+use crate::soundboard::{self, SoundClip};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const MUTE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Actions that can be bound to a global shortcut
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HotkeyAction {
+    Skip,
+    PauseResume,
+    Mute,
+    ReplayLast,
+}
+
+impl HotkeyAction {
+    pub fn run(self) {
+        match self {
+            HotkeyAction::Skip => PLAYBACK.skip(),
+            HotkeyAction::PauseResume => {
+                let paused = PLAYBACK.toggle_pause();
+                println!("TTS {}", if paused { "paused" } else { "resumed" });
+            }
+            HotkeyAction::Mute => PLAYBACK.mute_for(MUTE_DURATION),
+            HotkeyAction::ReplayLast => PLAYBACK.replay_last(),
+        }
+    }
+}
+
+/// A shortcut string such as "CommandOrControl+Shift+S" bound to an action
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HotkeyBinding {
+    pub action: HotkeyAction,
+    pub shortcut: String,
+}
+
+/// Replaces every registered global shortcut with the given bindings and soundboard clip hotkeys
+#[cfg(desktop)]
+pub fn register_hotkeys(
+    app: &tauri::AppHandle,
+    bindings: &[HotkeyBinding],
+    clips: &[SoundClip],
+) -> Result<(), String> {
+    use tauri_plugin_global_shortcut::{GlobalShortcutExt, ShortcutState};
+
+    let global_shortcut = app.global_shortcut();
+    global_shortcut
+        .unregister_all()
+        .map_err(|e| format!("Failed to unregister hotkeys: {}", e))?;
+
+    for binding in bindings {
+        let action = binding.action;
+        global_shortcut
+            .on_shortcut(binding.shortcut.as_str(), move |_app, _shortcut, event| {
+                if event.state() == ShortcutState::Pressed {
+                    println!("Hotkey pressed: {:?}", action);
+                    action.run();
+                }
+            })
+            .map_err(|e| format!("Failed to register hotkey {}: {}", binding.shortcut, e))?;
+        println!("Registered hotkey {} for {:?}", binding.shortcut, action);
+    }
+
+    for clip in clips {
+        let Some(shortcut) = clip.hotkey.as_deref() else {
+            continue;
+        };
+        let name = clip.name.clone();
+        global_shortcut
+            .on_shortcut(shortcut, move |_app, _shortcut, event| {
+                if event.state() == ShortcutState::Pressed {
+                    println!("Hotkey pressed: sound {}", name);
+                    if let Err(e) = soundboard::play(&name) {
+                        println!("Error playing sound: {}", e);
+                    }
+                }
+            })
+            .map_err(|e| format!("Failed to register hotkey {}: {}", shortcut, e))?;
+        println!("Registered hotkey {} for sound {}", shortcut, clip.name);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(desktop))]
+pub fn register_hotkeys(
+    _app: &tauri::AppHandle,
+    _bindings: &[HotkeyBinding],
+    _clips: &[SoundClip],
+) -> Result<(), String> {
+    Err("Global hotkeys are only supported on desktop".to_string())
+}
@@ -1,28 +1,70 @@
+mod alerts;
+mod audio;
 mod chat;
+mod commands;
+mod control;
+mod dsp;
+mod effects;
+mod export;
+mod hotkeys;
+mod queue;
+mod ratelimit;
+mod recorder;
+mod soundboard;
+mod spam;
 mod tts;
 
 use lazy_static::lazy_static;
-use piper_rs::synth::PiperSpeechSynthesizer;
-use std::env;
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use tauri::path::BaseDirectory;
 use tauri::Manager;
 
-use rodio::buffer::SamplesBuffer;
+use alerts::{AlertSound, AlertsConfig};
+use audio::{AudioConfig, Bus, BusConfig, OutputConfig};
+use control::PLAYBACK;
+use dsp::ProcessingConfig;
+use effects::EffectsConfig;
+use export::ExportRequest;
+use hotkeys::HotkeyBinding;
+use queue::{MergeConfig, PriorityConfig, QueueItem, QueueLimits, TTS_QUEUE};
+use ratelimit::{RateLimitConfig, RATE_LIMITER};
+use recorder::RecorderConfig;
+use soundboard::SoundboardConfig;
+use spam::{SpamConfig, SPAM_FILTER};
+use tts::{AdaptiveRateConfig, LengthLimits, SpeechConfig};
 
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::fs;
 use std::path::PathBuf;
-use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 
 #[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
 struct Config {
     twitch_username: String,
+    /// Lets the app log in as `twitch_username` to reply to chat commands
+    twitch_oauth_token: String,
     selected_speaker_id: i32,
+    /// Lowercase names of users whose messages are never read
+    muted_users: Vec<String>,
+    hotkeys: Vec<HotkeyBinding>,
+    priority: PriorityConfig,
+    queue_limits: QueueLimits,
+    adaptive_rate: AdaptiveRateConfig,
+    rate_limits: RateLimitConfig,
+    spam: SpamConfig,
+    merge: MergeConfig,
+    audio: AudioConfig,
+    speech: SpeechConfig,
+    processing: ProcessingConfig,
+    effects: EffectsConfig,
+    length_limits: LengthLimits,
+    alerts: AlertsConfig,
+    soundboard: SoundboardConfig,
+    recorder: RecorderConfig,
 }
 
 // Load config function using Tauri's config system
@@ -75,6 +117,20 @@ fn set_twitch_username(app: tauri::AppHandle, username: String) -> Result<String
     Ok("Username updated successfully".to_string())
 }
 
+#[tauri::command]
+fn set_twitch_oauth_token(app: tauri::AppHandle, token: String) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.twitch_oauth_token = token.trim().to_string();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    Ok("OAuth token updated successfully".to_string())
+}
+
+#[tauri::command]
+fn has_twitch_oauth_token(app: tauri::AppHandle) -> Result<bool, String> {
+    let config = load_config(&app);
+    Ok(!config.twitch_oauth_token.is_empty())
+}
+
 #[tauri::command]
 fn get_twitch_username(app: tauri::AppHandle) -> Result<String, String> {
     let config = load_config(&app);
@@ -83,29 +139,20 @@ fn get_twitch_username(app: tauri::AppHandle) -> Result<String, String> {
 
 #[tauri::command]
 fn print_config(app: tauri::AppHandle) -> Result<String, String> {
-    let config = load_config(&app);
+    let mut config = load_config(&app);
+    if !config.twitch_oauth_token.is_empty() {
+        config.twitch_oauth_token = "<hidden>".to_string();
+    }
     println!("Current config: {:?}", config);
     Ok("Config printed to console".to_string())
 }
 
 struct AppState {
-    synth: Option<PiperSpeechSynthesizer>,
-    tts_tx: Option<Sender<String>>,
-    tts_rx: Option<Receiver<String>>,
     kill_flag: Option<Arc<AtomicBool>>,
-    audio_tx: Option<Sender<Vec<f32>>>,
-    audio_rx: Option<Receiver<Vec<f32>>>,
 }
 
 lazy_static! {
-    static ref APP_STATE: Mutex<AppState> = Mutex::new(AppState {
-        synth: None,
-        tts_tx: None,
-        tts_rx: None,
-        kill_flag: None,
-        audio_tx: None,
-        audio_rx: None,
-    });
+    static ref APP_STATE: Mutex<AppState> = Mutex::new(AppState { kill_flag: None });
 }
 
 fn get_resources_dir(handle: tauri::AppHandle) -> PathBuf {
@@ -122,75 +169,68 @@ fn get_resources_dir(handle: tauri::AppHandle) -> PathBuf {
     }
 }
 
-// This command sythesizes and plays text
+// This command queues text to be spoken, after anything already queued
 #[tauri::command]
 fn synth_and_play_text(text: &str, handle: tauri::AppHandle) -> Result<String, String> {
-    // set PIPER_ESPEAKNG_DATA_DIRECTORY to the resources/espeak-ng folder
-    let resources_dir = get_resources_dir(handle.clone());
-    env::set_var(
-        "PIPER_ESPEAKNG_DATA_DIRECTORY",
-        resources_dir.to_string_lossy().to_string(),
-    );
-
-    // Read the variable back and log it to the console.
-    match env::var("PIPER_ESPEAKNG_DATA_DIRECTORY") {
-        Ok(val) => println!("PIPER_ESPEAKNG_DATA_DIRECTORY is set to: {}", val),
-        Err(e) => println!("Error reading env variable: {}", e),
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("Nothing to say, the text is empty".to_string());
     }
 
-    println!("Synthesizing and playing text: {}", text);
-    let text = text.to_string();
-    println!("Thread Synthesizing and playing text: {}", text);
-    let mut app_state = APP_STATE.lock().unwrap();
-    // if the synth is None, then we need to initialize it
-    if app_state.synth.is_none() {
-        // get the resources folder
-        let resources_dir = get_resources_dir(handle.clone());
-        let config_path = Path::new(&resources_dir).join("model.onnx.json");
-        let model = piper_rs::from_config_path(&config_path)
-            .map_err(|e| e.to_string())
-            .unwrap();
-
-        // Get selected speaker from config
-        let config = load_config(&handle);
-        // set the speaker to the selected speaker
-        model.set_speaker(config.selected_speaker_id as i64);
-
-        let synth = PiperSpeechSynthesizer::new(model)
-            .map_err(|e| e.to_string())
-            .unwrap();
-        app_state.synth = Some(synth);
-    }
-
-    // synthesize the text to speech
-    let mut samples: Vec<f32> = Vec::new();
-    let audio = match app_state
-        .synth
-        .as_ref()
-        .unwrap()
-        .synthesize_parallel(text, None)
-    {
-        Ok(audio) => audio,
-        Err(e) => {
-            return Ok(format!(
-                "Error synthesizing speech, Is this application in the applications folder?: {}",
-                e
-            ))
+    // typed text goes through the same loops as chat, start them if chat isn't connected
+    let kill_flag = {
+        let mut app_state = APP_STATE.lock().unwrap();
+        match &app_state.kill_flag {
+            Some(flag) if !flag.load(Ordering::SeqCst) => None,
+            _ => {
+                let kill_flag = Arc::new(AtomicBool::new(false));
+                app_state.kill_flag = Some(kill_flag.clone());
+                Some(kill_flag)
+            }
         }
     };
-    for result in audio {
-        samples.append(&mut result.unwrap().into_vec());
+    if let Some(kill_flag) = kill_flag {
+        start_tts_loops(&handle, &kill_flag);
     }
 
-    // play the audio
-    let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
-    let sink = rodio::Sink::try_new(&handle).unwrap();
-    let buf = SamplesBuffer::new(1, 22050, samples);
-    sink.append(buf);
-    sink.sleep_until_end();
-    println!("Thread finished synthesizing and playing");
+    println!("Queueing text: {}", text);
+    TTS_QUEUE.push_manual(text);
+    Ok("Text queued for speech".to_string())
+}
+
+/// Synthesizes text into an audio file instead of playing it
+#[tauri::command]
+async fn export_speech(
+    handle: tauri::AppHandle,
+    request: ExportRequest,
+    path: String,
+) -> Result<String, String> {
+    let config = load_config(&handle);
+    let resources_dir = get_resources_dir(handle.clone());
+    // synthesis can take a while, keep it off the async runtime
+    let duration = tokio::task::spawn_blocking(move || {
+        export::export_to_file(
+            &resources_dir,
+            &request,
+            &config.speech,
+            config.selected_speaker_id as i64,
+            Path::new(&path),
+        )
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    Ok(format!("Exported {:.1}s of speech", duration.as_secs_f32()))
+}
 
-    Ok("Finished TTS!".to_string())
+/// Runs `twitch-tools export ...` from the command line, returns the exit code
+pub fn run_export_cli(args: &[String]) -> i32 {
+    match export::run_cli(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
 }
 
 #[tauri::command]
@@ -202,86 +242,79 @@ async fn test_command(handle: tauri::AppHandle) -> Result<String, String> {
     Ok("Chat connection successful".to_string())
 }
 
+/// Starts the synth and audio loops, which run until the kill flag is set
+fn start_tts_loops(handle: &tauri::AppHandle, kill_flag: &Arc<AtomicBool>) {
+    // get vars for tts->audio thread
+    let resources_dir = get_resources_dir(handle.clone());
+    let handle_clone = handle.clone();
+    let synth_kill_flag = kill_flag.clone();
+
+    // create tts->audio thread
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            tts::synth_loop(&synth_kill_flag, &resources_dir, handle_clone)
+                .await
+                .unwrap();
+        });
+    });
+
+    // create audio->play thread
+    let audio_kill_flag = kill_flag.clone();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            tts::audio_loop(&audio_kill_flag).await.unwrap();
+        });
+    });
+}
+
 #[tauri::command]
 fn start_twitch_chat_reader(handle: tauri::AppHandle) -> Result<String, String> {
     let config = load_config(&handle);
 
-    // Create a channel for communication
-    let (tts_tx, tts_rx): (Sender<String>, Receiver<String>) = channel(); // Twitch -> TTS
-    let tts_tx_clone = tts_tx.clone();
     let kill_flag = Arc::new(AtomicBool::new(false)); // NEW
     let kill_flag_clone = kill_flag.clone();
 
     {
-        // set the tts_tx and tts_rx in the app state
         let mut app_state = APP_STATE.lock().unwrap();
         // Clear any existing kill flag and set the new one
         if let Some(existing_flag) = &app_state.kill_flag {
             existing_flag.store(true, Ordering::SeqCst); // Kill any existing reader
         }
-        app_state.tts_tx = Some(tts_tx);
-        app_state.tts_rx = Some(tts_rx);
         app_state.kill_flag = Some(kill_flag); // store for later kill
     };
+    // Messages from a previous session shouldn't be read out
+    TTS_QUEUE.clear();
+    if config.recorder.enabled && !recorder::is_recording() {
+        if let Err(e) = start_recording(&handle, &config.recorder) {
+            eprintln!("Error starting session recording: {}", e);
+        }
+    }
 
     let channel_name = config.twitch_username.clone();
+    let auth = (!config.twitch_oauth_token.is_empty()).then(|| chat::ChatAuth {
+        nickname: config.twitch_username.clone(),
+        oauth_token: config.twitch_oauth_token.clone(),
+    });
+    let chat_handle = handle.clone();
     thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
             if let Err(e) =
-                chat::start_twitch_chat_reader(&channel_name, &tts_tx_clone, &kill_flag_clone).await
+                chat::start_twitch_chat_reader(&channel_name, auth, &kill_flag_clone, chat_handle)
+                    .await
             {
                 eprintln!("Error in Twitch chat reader: {}", e);
             }
         });
     });
 
-    // create tts->audio channel of Vec<f32>
-    let (audio_tx, audio_rx): (Sender<Vec<f32>>, Receiver<Vec<f32>>) = channel(); // TTS -> Audio
-    {
-        let mut app_state = APP_STATE.lock().unwrap();
-        app_state.audio_tx = Some(audio_tx);
-        app_state.audio_rx = Some(audio_rx);
+    let kill_flag = {
+        let app_state = APP_STATE.lock().unwrap();
+        app_state.kill_flag.as_ref().unwrap().clone()
     };
-
-    // get vars for tts->audio thread
-    let resources_dir = get_resources_dir(handle.clone());
-    let handle_clone = handle.clone();
-    let (tts_rx, audio_tx, kill_flag) = {
-        let mut app_state = APP_STATE.lock().unwrap();
-        (
-            app_state.tts_rx.take().unwrap(),
-            app_state.audio_tx.as_ref().unwrap().clone(),
-            app_state.kill_flag.as_ref().unwrap().clone(),
-        )
-    };
-
-    // create tts->audio thread
-    thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
-            tts::synth_loop(tts_rx, &audio_tx, &kill_flag, &resources_dir, handle_clone)
-                .await
-                .unwrap();
-        });
-    });
-
-    // get vars for audio->play thread
-    let (audio_rx, kill_flag) = {
-        let mut app_state = APP_STATE.lock().unwrap();
-        (
-            app_state.audio_rx.take().unwrap(),
-            app_state.kill_flag.as_ref().unwrap().clone(),
-        )
-    };
-
-    // create audio->play thread
-    thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
-            tts::audio_loop(audio_rx, &kill_flag).await.unwrap();
-        });
-    });
+    start_tts_loops(&handle, &kill_flag);
 
     Ok("Twitch chat reader started".to_string())
 }
@@ -304,6 +337,11 @@ fn kill_twitch_chat_reader() -> Result<String, String> {
     if let Some(flag) = &app_state.kill_flag {
         println!("Setting kill flag");
         flag.store(true, Ordering::SeqCst); // Signal the thread to stop
+        if recorder::is_recording() {
+            if let Err(e) = recorder::stop() {
+                println!("Error stopping session recording: {}", e);
+            }
+        }
         Ok("Twitch chat reader kill signal sent.".to_string())
     } else {
         println!("No chat reader running.");
@@ -318,42 +356,639 @@ fn get_available_speakers(handle: tauri::AppHandle) -> Result<Vec<(i32, String)>
     Ok(speakers)
 }
 
+// Saves the speaker, the synth loop switches to it before the next queued item
+fn apply_speaker(app: &tauri::AppHandle, speaker_id: i32) -> Result<(), String> {
+    // check the model has the speaker before saving it
+    let resources_dir = get_resources_dir(app.clone());
+    if !tts::has_speaker(&resources_dir, speaker_id as i64)? {
+        return Err(format!("there is no voice {}", speaker_id));
+    }
+
+    let mut config = load_config(app);
+    config.selected_speaker_id = speaker_id;
+    save_config(app, &config).map_err(|e| e.to_string())?;
+
+    // the synth loop switches voices before its next message
+    tts::set_speaker(speaker_id as i64);
+
+    Ok(())
+}
+
 #[tauri::command]
 async fn set_selected_speaker(app: tauri::AppHandle, speaker_id: i32) -> Result<String, String> {
+    apply_speaker(&app, speaker_id)?;
+    Ok("Speaker updated successfully".to_string())
+}
+
+// Stops reading a user's messages and drops the ones already queued
+fn mute_user(app: &tauri::AppHandle, user: &str) -> Result<usize, String> {
+    let user = user.trim_start_matches('@').to_lowercase();
+    let mut config = load_config(app);
+    if !config.muted_users.contains(&user) {
+        config.muted_users.push(user.clone());
+        save_config(app, &config).map_err(|e| e.to_string())?;
+    }
+    RATE_LIMITER
+        .lock()
+        .unwrap()
+        .set_muted_users(&config.muted_users);
+    Ok(TTS_QUEUE.remove_user(&user))
+}
+
+// Returns false if the user wasn't muted
+fn unmute_user(app: &tauri::AppHandle, user: &str) -> Result<bool, String> {
+    let user = user.trim_start_matches('@').to_lowercase();
+    let mut config = load_config(app);
+    let before = config.muted_users.len();
+    config.muted_users.retain(|muted| muted != &user);
+    if config.muted_users.len() == before {
+        return Ok(false);
+    }
+    save_config(app, &config).map_err(|e| e.to_string())?;
+    RATE_LIMITER
+        .lock()
+        .unwrap()
+        .set_muted_users(&config.muted_users);
+    Ok(true)
+}
+
+#[tauri::command]
+fn get_muted_users(app: tauri::AppHandle) -> Result<Vec<String>, String> {
+    let config = load_config(&app);
+    Ok(config.muted_users)
+}
+
+#[tauri::command]
+fn mute_chat_user(app: tauri::AppHandle, user: String) -> Result<String, String> {
+    let removed = mute_user(&app, &user)?;
+    Ok(format!(
+        "Muted {} and removed {} queued messages",
+        user, removed
+    ))
+}
+
+#[tauri::command]
+fn unmute_chat_user(app: tauri::AppHandle, user: String) -> Result<String, String> {
+    if unmute_user(&app, &user)? {
+        Ok(format!("Unmuted {}", user))
+    } else {
+        Err(format!("{} is not muted", user))
+    }
+}
+
+#[tauri::command]
+fn skip_tts() -> Result<String, String> {
+    PLAYBACK.skip();
+    Ok("Skipped current message".to_string())
+}
+
+#[tauri::command]
+fn pause_tts() -> Result<String, String> {
+    PLAYBACK.pause();
+    Ok("TTS paused".to_string())
+}
+
+#[tauri::command]
+fn resume_tts() -> Result<String, String> {
+    PLAYBACK.resume();
+    Ok("TTS resumed".to_string())
+}
+
+#[tauri::command]
+fn mute_tts(minutes: u64) -> Result<String, String> {
+    PLAYBACK.mute_for(std::time::Duration::from_secs(minutes * 60));
+    Ok(format!("TTS muted for {} minutes", minutes))
+}
+
+#[tauri::command]
+fn unmute_tts() -> Result<String, String> {
+    PLAYBACK.unmute();
+    Ok("TTS unmuted".to_string())
+}
+
+#[tauri::command]
+fn replay_last_tts() -> Result<String, String> {
+    PLAYBACK.replay_last();
+    Ok("Replaying last message".to_string())
+}
+
+#[tauri::command]
+fn get_hotkeys(app: tauri::AppHandle) -> Result<Vec<HotkeyBinding>, String> {
+    let config = load_config(&app);
+    Ok(config.hotkeys)
+}
+
+#[tauri::command]
+fn set_hotkeys(app: tauri::AppHandle, hotkeys: Vec<HotkeyBinding>) -> Result<String, String> {
     let mut config = load_config(&app);
-    config.selected_speaker_id = speaker_id;
+    // Register first so an invalid shortcut never gets saved
+    if let Err(e) = hotkeys::register_hotkeys(&app, &hotkeys, &config.soundboard.clips) {
+        // put the previous bindings back
+        let _ = hotkeys::register_hotkeys(&app, &config.hotkeys, &config.soundboard.clips);
+        return Err(e);
+    }
+    config.hotkeys = hotkeys;
     save_config(&app, &config).map_err(|e| e.to_string())?;
+    Ok("Hotkeys updated successfully".to_string())
+}
 
-    // Reinitialize the synthesizer with the new speaker in a background thread
-    let resources_dir = get_resources_dir(app.clone());
-    let config_path = Path::new(&resources_dir).join("model.onnx.json");
-    let model = piper_rs::from_config_path(&config_path).map_err(|e| e.to_string())?;
-    model.set_speaker(speaker_id as i64);
+#[tauri::command]
+fn get_tts_queue() -> Result<Vec<QueueItem>, String> {
+    Ok(TTS_QUEUE.list())
+}
 
-    let new_synth = PiperSpeechSynthesizer::new(model).map_err(|e| e.to_string())?;
+#[tauri::command]
+fn remove_tts_queue_item(id: String) -> Result<String, String> {
+    if TTS_QUEUE.remove(&id) {
+        Ok("Message removed from queue".to_string())
+    } else {
+        Err("Message is no longer in the queue".to_string())
+    }
+}
 
-    // Only lock APP_STATE for the brief moment we need to update the synthesizer
-    let mut app_state = APP_STATE.lock().unwrap();
-    app_state.synth = Some(new_synth);
+#[tauri::command]
+fn move_tts_queue_item_to_front(id: String) -> Result<String, String> {
+    if TTS_QUEUE.move_to_front(&id) {
+        Ok("Message moved to the front of the queue".to_string())
+    } else {
+        Err("Message is no longer in the queue".to_string())
+    }
+}
 
-    Ok("Speaker updated successfully".to_string())
+#[tauri::command]
+fn clear_tts_queue() -> Result<String, String> {
+    let count = TTS_QUEUE.clear();
+    Ok(format!("Removed {} messages from the queue", count))
+}
+
+#[tauri::command]
+fn get_priority_config(app: tauri::AppHandle) -> Result<PriorityConfig, String> {
+    let config = load_config(&app);
+    Ok(config.priority)
+}
+
+#[tauri::command]
+fn set_priority_config(app: tauri::AppHandle, priority: PriorityConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.priority = priority.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    TTS_QUEUE.set_priority_config(priority);
+    Ok("Priority settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_queue_limits(app: tauri::AppHandle) -> Result<QueueLimits, String> {
+    let config = load_config(&app);
+    Ok(config.queue_limits)
+}
+
+#[tauri::command]
+fn set_queue_limits(app: tauri::AppHandle, limits: QueueLimits) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.queue_limits = limits.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    TTS_QUEUE.set_limits(limits);
+    Ok("Queue limits updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_adaptive_rate_config(app: tauri::AppHandle) -> Result<AdaptiveRateConfig, String> {
+    let config = load_config(&app);
+    Ok(config.adaptive_rate)
+}
+
+#[tauri::command]
+fn set_adaptive_rate_config(
+    app: tauri::AppHandle,
+    adaptive_rate: AdaptiveRateConfig,
+) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.adaptive_rate = adaptive_rate.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    tts::set_adaptive_rate_config(adaptive_rate);
+    Ok("Adaptive rate settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_rate_limits(app: tauri::AppHandle) -> Result<RateLimitConfig, String> {
+    let config = load_config(&app);
+    Ok(config.rate_limits)
+}
+
+#[tauri::command]
+fn set_rate_limits(app: tauri::AppHandle, rate_limits: RateLimitConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.rate_limits = rate_limits.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    RATE_LIMITER.lock().unwrap().set_config(rate_limits);
+    Ok("Rate limits updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_spam_config(app: tauri::AppHandle) -> Result<SpamConfig, String> {
+    let config = load_config(&app);
+    Ok(config.spam)
+}
+
+#[tauri::command]
+fn set_spam_config(app: tauri::AppHandle, spam: SpamConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.spam = spam.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    SPAM_FILTER.lock().unwrap().set_config(spam);
+    Ok("Spam settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_merge_config(app: tauri::AppHandle) -> Result<MergeConfig, String> {
+    let config = load_config(&app);
+    Ok(config.merge)
+}
+
+#[tauri::command]
+fn set_merge_config(app: tauri::AppHandle, merge: MergeConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.merge = merge.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    TTS_QUEUE.set_merge_config(merge);
+    Ok("Merge settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn set_master_volume(app: tauri::AppHandle, volume: f32) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.audio.master_volume = volume.max(0.0);
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    audio::set_audio_config(config.audio);
+    Ok("Master volume updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_speech_config(app: tauri::AppHandle) -> Result<SpeechConfig, String> {
+    let config = load_config(&app);
+    Ok(config.speech)
+}
+
+#[tauri::command]
+fn set_speech_config(app: tauri::AppHandle, speech: SpeechConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.speech = speech.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    tts::set_speech_config(speech);
+    Ok("Speech settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_processing_config(app: tauri::AppHandle) -> Result<ProcessingConfig, String> {
+    let config = load_config(&app);
+    Ok(config.processing)
+}
+
+#[tauri::command]
+fn set_processing_config(
+    app: tauri::AppHandle,
+    processing: ProcessingConfig,
+) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.processing = processing.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    dsp::set_processing_config(processing);
+    Ok("Processing settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_effects_config(app: tauri::AppHandle) -> Result<EffectsConfig, String> {
+    let config = load_config(&app);
+    Ok(config.effects)
+}
+
+#[tauri::command]
+fn set_effects_config(app: tauri::AppHandle, effects: EffectsConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.effects = effects.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    effects::set_effects_config(effects);
+    Ok("Effects settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_length_limits(app: tauri::AppHandle) -> Result<LengthLimits, String> {
+    let config = load_config(&app);
+    Ok(config.length_limits)
+}
+
+#[tauri::command]
+fn set_length_limits(app: tauri::AppHandle, length_limits: LengthLimits) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.length_limits = length_limits.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    tts::set_length_limits(length_limits);
+    Ok("Length limits updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_alerts_config(app: tauri::AppHandle) -> Result<AlertsConfig, String> {
+    let config = load_config(&app);
+    Ok(config.alerts)
+}
+
+#[tauri::command]
+fn set_alerts_config(app: tauri::AppHandle, alerts: AlertsConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.alerts = alerts.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    alerts::set_alerts_config(alerts);
+    Ok("Alert settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_alert_sounds() -> Result<Vec<String>, String> {
+    alerts::list_sounds()
+}
+
+#[tauri::command]
+fn get_sound_library_dir() -> Result<String, String> {
+    Ok(alerts::library_dir()?.to_string_lossy().to_string())
+}
+
+#[tauri::command]
+fn play_alert_sound(sound: AlertSound) -> Result<String, String> {
+    alerts::play(&sound)?;
+    Ok(format!("Playing {}", sound.file))
+}
+
+#[tauri::command]
+fn get_soundboard_config(app: tauri::AppHandle) -> Result<SoundboardConfig, String> {
+    let config = load_config(&app);
+    Ok(config.soundboard)
+}
+
+#[tauri::command]
+fn set_soundboard_config(
+    app: tauri::AppHandle,
+    soundboard: SoundboardConfig,
+) -> Result<String, String> {
+    let mut config = load_config(&app);
+    // Register first so an invalid clip hotkey never gets saved
+    if let Err(e) = hotkeys::register_hotkeys(&app, &config.hotkeys, &soundboard.clips) {
+        let _ = hotkeys::register_hotkeys(&app, &config.hotkeys, &config.soundboard.clips);
+        return Err(e);
+    }
+    config.soundboard = soundboard.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    soundboard::set_soundboard_config(soundboard);
+    Ok("Soundboard updated successfully".to_string())
+}
+
+#[tauri::command]
+fn play_soundboard_clip(name: String) -> Result<String, String> {
+    soundboard::play(&name)
+}
+
+fn start_recording(app: &tauri::AppHandle, config: &RecorderConfig) -> Result<PathBuf, String> {
+    let directory = match &config.directory {
+        Some(directory) => PathBuf::from(directory),
+        None => app
+            .path()
+            .resolve("recordings", BaseDirectory::AppData)
+            .map_err(|e| e.to_string())?,
+    };
+    recorder::start(&directory)
+}
+
+#[tauri::command]
+fn get_recorder_config(app: tauri::AppHandle) -> Result<RecorderConfig, String> {
+    let config = load_config(&app);
+    Ok(config.recorder)
+}
+
+#[tauri::command]
+fn set_recorder_config(app: tauri::AppHandle, recorder: RecorderConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.recorder = recorder;
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    Ok("Recorder settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn start_session_recording(app: tauri::AppHandle) -> Result<String, String> {
+    let config = load_config(&app);
+    let path = start_recording(&app, &config.recorder)?;
+    Ok(format!("Recording to {}", path.display()))
+}
+
+#[tauri::command]
+fn stop_session_recording() -> Result<String, String> {
+    let path = recorder::stop()?;
+    Ok(format!("Recording saved to {}", path.display()))
+}
+
+#[tauri::command]
+fn is_session_recording() -> Result<bool, String> {
+    Ok(recorder::is_recording())
+}
+
+#[tauri::command]
+fn get_output_devices() -> Result<Vec<String>, String> {
+    audio::list_output_devices()
+}
+
+#[tauri::command]
+fn set_output_devices(app: tauri::AppHandle, outputs: Vec<OutputConfig>) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.audio.outputs = outputs;
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    audio::set_audio_config(config.audio);
+    Ok("Output devices updated successfully".to_string())
+}
+
+#[tauri::command]
+fn get_audio_config(app: tauri::AppHandle) -> Result<AudioConfig, String> {
+    let config = load_config(&app);
+    Ok(config.audio)
+}
+
+#[tauri::command]
+fn set_audio_config(app: tauri::AppHandle, audio: AudioConfig) -> Result<String, String> {
+    let mut config = load_config(&app);
+    if audio.music != config.audio.music {
+        alerts::set_music(audio.music.as_deref())?;
+    }
+    config.audio = audio.clone();
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    audio::set_audio_config(audio);
+    Ok("Audio settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn set_bus_config(
+    app: tauri::AppHandle,
+    bus: Bus,
+    bus_config: BusConfig,
+) -> Result<String, String> {
+    let mut config = load_config(&app);
+    config.audio.buses.insert(bus, bus_config);
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    audio::set_audio_config(config.audio);
+    Ok("Bus settings updated successfully".to_string())
+}
+
+#[tauri::command]
+fn set_background_music(app: tauri::AppHandle, file: Option<String>) -> Result<String, String> {
+    let mut config = load_config(&app);
+    // Load first so a missing file never gets saved
+    alerts::set_music(file.as_deref())?;
+    config.audio.music = file;
+    save_config(&app, &config).map_err(|e| e.to_string())?;
+    Ok("Background music updated successfully".to_string())
+}
+
+#[derive(Serialize)]
+struct PipelineStatus {
+    running: bool,
+    queue_length: usize,
+    waiting: usize,
+    oldest_waiting_secs: f32,
+    length_scale: f32,
+}
+
+#[tauri::command]
+fn get_pipeline_status() -> Result<PipelineStatus, String> {
+    let running = {
+        let app_state = APP_STATE.lock().unwrap();
+        app_state
+            .kill_flag
+            .as_ref()
+            .is_some_and(|flag| !flag.load(Ordering::SeqCst))
+    };
+    let (waiting, oldest_waiting) = TTS_QUEUE.backlog();
+    Ok(PipelineStatus {
+        running,
+        queue_length: TTS_QUEUE.list().len(),
+        waiting,
+        oldest_waiting_secs: oldest_waiting.as_secs_f32(),
+        length_scale: tts::current_length_scale(),
+    })
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    tauri::Builder::default()
+    let builder = tauri::Builder::default();
+    #[cfg(desktop)]
+    let builder = builder.plugin(tauri_plugin_global_shortcut::Builder::new().build());
+
+    builder
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_fs::init())
+        .setup(|app| {
+            TTS_QUEUE.set_app_handle(app.handle().clone());
+            let config = load_config(app.handle());
+            TTS_QUEUE.set_priority_config(config.priority.clone());
+            TTS_QUEUE.set_limits(config.queue_limits.clone());
+            TTS_QUEUE.set_merge_config(config.merge.clone());
+            tts::set_adaptive_rate_config(config.adaptive_rate.clone());
+            tts::set_speech_config(config.speech.clone());
+            dsp::set_processing_config(config.processing.clone());
+            effects::set_effects_config(config.effects.clone());
+            tts::set_length_limits(config.length_limits.clone());
+            match app.path().resolve("sounds", BaseDirectory::AppData) {
+                Ok(dir) => alerts::set_library_dir(dir),
+                Err(e) => eprintln!("Error resolving sound library: {}", e),
+            }
+            alerts::set_alerts_config(config.alerts.clone());
+            if let Err(e) = alerts::set_music(config.audio.music.as_deref()) {
+                eprintln!("Error loading background music: {}", e);
+            }
+            soundboard::set_soundboard_config(config.soundboard.clone());
+            RATE_LIMITER
+                .lock()
+                .unwrap()
+                .set_config(config.rate_limits.clone());
+            RATE_LIMITER
+                .lock()
+                .unwrap()
+                .set_muted_users(&config.muted_users);
+            SPAM_FILTER.lock().unwrap().set_config(config.spam.clone());
+            audio::set_app_handle(app.handle().clone());
+            audio::set_audio_config(config.audio.clone());
+            audio::start_device_monitor();
+            audio::start_ducking();
+            if let Err(e) =
+                hotkeys::register_hotkeys(app.handle(), &config.hotkeys, &config.soundboard.clips)
+            {
+                eprintln!("Error registering hotkeys: {}", e);
+            }
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             synth_and_play_text,
+            export_speech,
             test_command,
             set_twitch_username,
             get_twitch_username,
+            set_twitch_oauth_token,
+            has_twitch_oauth_token,
             print_config,
             start_twitch_chat_reader,
             kill_twitch_chat_reader,
             get_available_speakers,
             set_selected_speaker,
+            get_muted_users,
+            mute_chat_user,
+            unmute_chat_user,
+            skip_tts,
+            pause_tts,
+            resume_tts,
+            mute_tts,
+            unmute_tts,
+            replay_last_tts,
+            get_hotkeys,
+            set_hotkeys,
+            get_tts_queue,
+            remove_tts_queue_item,
+            move_tts_queue_item_to_front,
+            clear_tts_queue,
+            get_priority_config,
+            set_priority_config,
+            get_queue_limits,
+            set_queue_limits,
+            get_adaptive_rate_config,
+            set_adaptive_rate_config,
+            get_pipeline_status,
+            get_rate_limits,
+            set_rate_limits,
+            get_spam_config,
+            set_spam_config,
+            get_merge_config,
+            set_merge_config,
+            get_audio_config,
+            set_audio_config,
+            get_output_devices,
+            set_output_devices,
+            set_master_volume,
+            set_bus_config,
+            set_background_music,
+            get_speech_config,
+            set_speech_config,
+            get_processing_config,
+            set_processing_config,
+            get_effects_config,
+            set_effects_config,
+            get_length_limits,
+            set_length_limits,
+            get_alerts_config,
+            set_alerts_config,
+            get_alert_sounds,
+            get_sound_library_dir,
+            play_alert_sound,
+            get_soundboard_config,
+            set_soundboard_config,
+            play_soundboard_clip,
+            get_recorder_config,
+            set_recorder_config,
+            start_session_recording,
+            stop_session_recording,
+            is_session_recording,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -2,5 +2,29 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|command| command == "export") {
+        attach_console();
+        std::process::exit(twitch_tools_lib::run_export_cli(&args[1..]));
+    }
     twitch_tools_lib::run()
 }
+
+/// Release builds on Windows start without a console, so the CLI borrows the one of the
+/// shell that started it, otherwise its output and errors would never be shown.
+/// The shell doesn't wait for a windows subsystem app, so the prompt may come back first.
+#[cfg(all(windows, not(debug_assertions)))]
+fn attach_console() {
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    // fails when started without a console, there is nowhere to print to then anyway
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(all(windows, not(debug_assertions))))]
+fn attach_console() {}
@@ -0,0 +1,783 @@
+use crate::audio::AudioBuffer;
+use crate::chat::{ChatMessage, MessageKind, UserRole};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter};
+
+/// Event emitted with the full list of pending items whenever the queue changes
+pub const QUEUE_CHANGED_EVENT: &str = "tts-queue-changed";
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemState {
+    Waiting,
+    /// Audio is being produced, the item can start playing once it reaches the front
+    Synthesizing,
+    Ready,
+}
+
+/// Weights used to decide which messages jump ahead in the queue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PriorityConfig {
+    /// Text typed into the app by the streamer
+    pub manual: i32,
+    pub cheer: i32,
+    /// Extra priority for every this many bits cheered, 0 disables it
+    pub bits_per_point: u32,
+    pub redemption: i32,
+    pub raid: i32,
+    pub subscription: i32,
+    pub broadcaster: i32,
+    pub moderator: i32,
+    pub vip: i32,
+    pub subscriber: i32,
+    /// Waiting messages gain one point of priority every this many seconds,
+    /// so regular chat still plays during a busy stream. 0 disables it
+    pub aging_secs: u64,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        PriorityConfig {
+            manual: 100,
+            cheer: 20,
+            bits_per_point: 100,
+            redemption: 30,
+            raid: 50,
+            subscription: 40,
+            broadcaster: 30,
+            moderator: 20,
+            vip: 10,
+            subscriber: 5,
+            aging_secs: 10,
+        }
+    }
+}
+
+impl PriorityConfig {
+    pub fn priority_for(&self, message: &ChatMessage) -> i32 {
+        let event = match message.kind {
+            MessageKind::Chat | MessageKind::System | MessageKind::Sound => 0,
+            MessageKind::Manual => self.manual,
+            MessageKind::Cheer => self.cheer,
+            MessageKind::Redemption => self.redemption,
+            MessageKind::Raid => self.raid,
+            MessageKind::Subscription => self.subscription,
+        };
+        let role = match message.role {
+            UserRole::Viewer => 0,
+            UserRole::Subscriber => self.subscriber,
+            UserRole::Vip => self.vip,
+            UserRole::Moderator => self.moderator,
+            UserRole::Broadcaster => self.broadcaster,
+        };
+        let bits = message
+            .bits
+            .checked_div(self.bits_per_point)
+            .unwrap_or_default() as i32;
+        event + role + bits
+    }
+}
+
+/// Which message gets dropped when the queue is full
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    Oldest,
+    Newest,
+    LowestPriority,
+}
+
+/// Limits that keep TTS from falling behind a busy chat
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QueueLimits {
+    /// Maximum number of pending messages, 0 for no limit.
+    /// The "skipped N messages" summary doesn't count towards it.
+    pub max_length: usize,
+    pub drop_policy: DropPolicy,
+    /// Messages waiting longer than this are discarded, 0 to keep them forever
+    pub max_age_secs: u64,
+    /// Speak "skipped N messages" when messages are dropped or expire
+    pub announce_skipped: bool,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            max_length: 25,
+            drop_policy: DropPolicy::LowestPriority,
+            max_age_secs: 180,
+            announce_skipped: true,
+        }
+    }
+}
+
+/// Combines quick consecutive chat messages from one user into a single item
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MergeConfig {
+    pub enabled: bool,
+    /// Merge a message into the previous one if it arrives within this many seconds
+    pub window_secs: u64,
+    /// Stop merging once the combined text would be longer than this
+    pub max_length: usize,
+}
+
+impl Default for MergeConfig {
+    fn default() -> Self {
+        MergeConfig {
+            enabled: true,
+            window_secs: 5,
+            max_length: 300,
+        }
+    }
+}
+
+/// Summaries are read out before anything else
+const SUMMARY_PRIORITY: i32 = i32::MAX;
+
+/// A chat message waiting to be spoken, as shown to the UI
+#[derive(Serialize, Clone, Debug)]
+pub struct QueueItem {
+    pub id: String,
+    pub user: String,
+    pub text: String,
+    pub kind: MessageKind,
+    pub priority: i32,
+    /// How many other users sent the same message while this one was queued
+    pub repeats: usize,
+    pub bits: u32,
+    /// Channel point reward the message was sent with
+    pub reward_id: Option<String>,
+    /// Twitch's description of a raid or sub, which `text` starts with
+    pub notice: Option<String>,
+    /// Milliseconds since the unix epoch
+    pub enqueued_at: u64,
+    pub state: ItemState,
+}
+
+struct Entry {
+    item: QueueItem,
+    /// Chunks of audio streamed in by the synth loop while the item is synthesizing
+    audio: Option<Receiver<AudioBuffer>>,
+    /// Set when the item is moved to the front, newer pins go first
+    pinned: Option<u64>,
+    /// For summary items, how many messages were skipped
+    skipped: usize,
+    /// When text was last added to this item, used to find merge candidates
+    updated_at: u64,
+}
+
+impl Entry {
+    fn new(user: &str, text: &str, kind: MessageKind, priority: i32) -> Entry {
+        let now = now_millis();
+        Entry {
+            item: QueueItem {
+                id: uuid::Uuid::new_v4().to_string(),
+                user: user.to_string(),
+                text: text.to_string(),
+                kind,
+                priority,
+                repeats: 0,
+                bits: 0,
+                reward_id: None,
+                notice: None,
+                enqueued_at: now,
+                state: ItemState::Waiting,
+            },
+            audio: None,
+            pinned: None,
+            skipped: 0,
+            updated_at: now,
+        }
+    }
+
+    /// Only waiting items can be dropped by the queue limits, pinned items, summaries
+    /// and text the streamer typed are kept, as is anything already being synthesized or played
+    fn droppable(&self) -> bool {
+        self.item.state == ItemState::Waiting
+            && self.pinned.is_none()
+            && !matches!(self.item.kind, MessageKind::System | MessageKind::Manual)
+    }
+
+    fn effective_priority(&self, now: u64, aging_secs: u64) -> i64 {
+        let aging = if aging_secs > 0 {
+            now.saturating_sub(self.item.enqueued_at) / (aging_secs * 1000)
+        } else {
+            0
+        };
+        self.item.priority as i64 + aging as i64
+    }
+}
+
+/// Finds the item a new message can be appended to: the most recently updated
+/// item, if it is still waiting and is a chat message from the same user
+fn merge_target<'a>(
+    entries: &'a mut VecDeque<Entry>,
+    message: &ChatMessage,
+    merge: &MergeConfig,
+) -> Option<&'a mut Entry> {
+    if !merge.enabled || message.kind != MessageKind::Chat {
+        return None;
+    }
+    let last = entries.iter_mut().max_by_key(|entry| entry.updated_at)?;
+    let fits = last.item.state == ItemState::Waiting
+        && last.item.kind == MessageKind::Chat
+        && last.item.user.eq_ignore_ascii_case(&message.username)
+        && now_millis().saturating_sub(last.updated_at) <= merge.window_secs * 1000
+        && last.item.text.len() + message.content.len() + 2 <= merge.max_length;
+    fits.then_some(last)
+}
+
+/// Orders waiting entries by pin, then priority including aging, then arrival.
+/// Entries that are synthesizing or ready stay at the front in the order they were
+/// started, so aging can't move a newer message ahead of one that is already playing out.
+fn sort_entries(entries: &mut VecDeque<Entry>, aging_secs: u64) {
+    let now = now_millis();
+    entries.make_contiguous().sort_by_key(|entry| {
+        if entry.item.state != ItemState::Waiting {
+            // the sort is stable, so equal keys keep their current order
+            return (false, Reverse(None), Reverse(0), 0);
+        }
+        (
+            true,
+            Reverse(entry.pinned),
+            Reverse(entry.effective_priority(now, aging_secs)),
+            entry.item.enqueued_at,
+        )
+    });
+}
+
+fn summary_text(skipped: usize) -> String {
+    if skipped == 1 {
+        "skipped 1 message".to_string()
+    } else {
+        format!("skipped {} messages", skipped)
+    }
+}
+
+/// Adds the number of dropped messages to the pending summary, or queues a new one
+fn add_summary(entries: &mut VecDeque<Entry>, dropped: usize) {
+    let pending = entries
+        .iter_mut()
+        .find(|entry| entry.skipped > 0 && entry.item.state == ItemState::Waiting);
+    match pending {
+        Some(entry) => {
+            entry.skipped += dropped;
+            entry.item.text = summary_text(entry.skipped);
+        }
+        None => {
+            let mut entry = Entry::new(
+                "",
+                &summary_text(dropped),
+                MessageKind::System,
+                SUMMARY_PRIORITY,
+            );
+            entry.skipped = dropped;
+            entries.push_back(entry);
+        }
+    }
+}
+
+/// Expires old messages, drops messages over the length limit and sorts the queue.
+/// Returns how many messages were dropped.
+fn tidy(entries: &mut VecDeque<Entry>, aging_secs: u64, limits: &QueueLimits) -> usize {
+    let before = entries.len();
+
+    if limits.max_age_secs > 0 {
+        let now = now_millis();
+        let max_age = limits.max_age_secs * 1000;
+        entries.retain(|entry| {
+            !entry.droppable() || now.saturating_sub(entry.item.enqueued_at) <= max_age
+        });
+    }
+
+    sort_entries(entries, aging_secs);
+    // summaries are left out so announcing a drop doesn't cost another message
+    let counted = |entries: &VecDeque<Entry>| {
+        entries
+            .iter()
+            .filter(|entry| entry.item.kind != MessageKind::System)
+            .count()
+    };
+    while limits.max_length > 0 && counted(entries) > limits.max_length {
+        let mut droppable = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.droppable());
+        let victim = match limits.drop_policy {
+            DropPolicy::Oldest => droppable.min_by_key(|(_, entry)| entry.item.enqueued_at),
+            DropPolicy::Newest => droppable.max_by_key(|(_, entry)| entry.item.enqueued_at),
+            // the queue is sorted, so the last droppable entry would play last
+            DropPolicy::LowestPriority => droppable.next_back(),
+        };
+        match victim.map(|(index, _)| index) {
+            Some(index) => {
+                entries.remove(index);
+            }
+            None => break,
+        }
+    }
+
+    let dropped = before - entries.len();
+    if dropped > 0 {
+        println!("Dropped {} messages from the TTS queue", dropped);
+        if limits.announce_skipped {
+            add_summary(entries, dropped);
+            sort_entries(entries, aging_secs);
+        }
+    }
+    dropped
+}
+
+/// The queue between the chat reader, the synth loop and the audio loop.
+///
+/// Items stay in the queue until the audio loop takes them for playback, so the
+/// UI can see and edit everything that hasn't been spoken yet. The queue is kept
+/// sorted by priority, the synth loop works on the first waiting item and the
+/// audio loop only plays the front item, so playback always follows queue order.
+pub struct TtsQueue {
+    entries: Mutex<VecDeque<Entry>>,
+    changed: Condvar,
+    priority: Mutex<PriorityConfig>,
+    limits: Mutex<QueueLimits>,
+    merge: Mutex<MergeConfig>,
+    next_pin: AtomicU64,
+    app_handle: Mutex<Option<AppHandle>>,
+}
+
+lazy_static! {
+    pub static ref TTS_QUEUE: TtsQueue = TtsQueue {
+        entries: Mutex::new(VecDeque::new()),
+        changed: Condvar::new(),
+        priority: Mutex::new(PriorityConfig::default()),
+        limits: Mutex::new(QueueLimits::default()),
+        merge: Mutex::new(MergeConfig::default()),
+        next_pin: AtomicU64::new(1),
+        app_handle: Mutex::new(None),
+    };
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl TtsQueue {
+    /// Sets the handle used to emit change events to the UI
+    pub fn set_app_handle(&self, handle: AppHandle) {
+        *self.app_handle.lock().unwrap() = Some(handle);
+    }
+
+    pub fn set_priority_config(&self, config: PriorityConfig) {
+        *self.priority.lock().unwrap() = config;
+        let entries = self.entries.lock().unwrap();
+        self.notify(entries);
+    }
+
+    pub fn set_limits(&self, limits: QueueLimits) {
+        *self.limits.lock().unwrap() = limits;
+        let entries = self.entries.lock().unwrap();
+        self.notify(entries);
+    }
+
+    pub fn set_merge_config(&self, merge: MergeConfig) {
+        *self.merge.lock().unwrap() = merge;
+    }
+
+    fn aging_secs(&self) -> u64 {
+        self.priority.lock().unwrap().aging_secs
+    }
+
+    fn limits(&self) -> QueueLimits {
+        self.limits.lock().unwrap().clone()
+    }
+
+    /// Appends a quick follow-up chat message to the same user's previous item.
+    /// Returns the id of the item it was merged into, or gives the message back
+    /// so the caller can check the rate limits and push it as a new item.
+    pub fn merge_message<'a>(&self, message: &'a ChatMessage) -> Result<String, &'a ChatMessage> {
+        let priority = self.priority.lock().unwrap().priority_for(message);
+        let merge = self.merge.lock().unwrap().clone();
+
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = merge_target(&mut entries, message, &merge) else {
+            return Err(message);
+        };
+        let separator = if entry.item.text.ends_with(['.', '!', '?']) {
+            " "
+        } else {
+            ". "
+        };
+        entry.item.text = format!("{}{}{}", entry.item.text, separator, message.content);
+        entry.item.priority = entry.item.priority.max(priority);
+        entry.item.bits = entry.item.bits.saturating_add(message.bits);
+        entry.updated_at = now_millis();
+        let id = entry.item.id.clone();
+        println!("Merged message from {} into {}", message.username, id);
+        self.notify(entries);
+        Ok(id)
+    }
+
+    /// Queues a chat message as a new item using the configured priority weights
+    /// and returns its id
+    pub fn push_message(&self, message: &ChatMessage) -> String {
+        let priority = self.priority.lock().unwrap().priority_for(message);
+        let mut entry = Entry::new(&message.username, &message.content, message.kind, priority);
+        entry.item.bits = message.bits;
+        entry.item.reward_id = message.reward_id.clone();
+        entry.item.notice = message.notice.clone();
+        self.push_entry(entry)
+    }
+
+    /// Queues an item that didn't come from a chat message and returns its id
+    pub fn push(&self, user: &str, text: &str, kind: MessageKind, priority: i32) -> String {
+        self.push_entry(Entry::new(user, text, kind, priority))
+    }
+
+    /// Adds text typed by the streamer, it plays ahead of chat and is never dropped
+    pub fn push_manual(&self, text: &str) -> String {
+        let priority = self.priority.lock().unwrap().manual;
+        self.push("", text, MessageKind::Manual, priority)
+    }
+
+    /// Adds an item behind everything with the same or higher priority and returns its id.
+    /// The item may be dropped straight away if the queue is full.
+    fn push_entry(&self, entry: Entry) -> String {
+        let id = entry.item.id.clone();
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(entry);
+        self.notify(entries);
+        id
+    }
+
+    /// Returns every pending item in playback order
+    pub fn list(&self) -> Vec<QueueItem> {
+        let aging_secs = self.aging_secs();
+        let mut entries = self.entries.lock().unwrap();
+        sort_entries(&mut entries, aging_secs);
+        entries.iter().map(|entry| entry.item.clone()).collect()
+    }
+
+    /// Returns how many items are waiting to be synthesized and how long the oldest has waited
+    pub fn backlog(&self) -> (usize, Duration) {
+        let entries = self.entries.lock().unwrap();
+        let waiting = entries
+            .iter()
+            .filter(|entry| entry.item.state == ItemState::Waiting);
+        let count = waiting.clone().count();
+        let oldest = waiting
+            .map(|entry| entry.item.enqueued_at)
+            .min()
+            .map(|enqueued_at| Duration::from_millis(now_millis().saturating_sub(enqueued_at)))
+            .unwrap_or_default();
+        (count, oldest)
+    }
+
+    /// Returns how many items from a user are still pending
+    pub fn pending_for_user(&self, user: &str) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|entry| entry.item.user.eq_ignore_ascii_case(user))
+            .count()
+    }
+
+    /// Counts another user sending the same message, returns false if the
+    /// item was already synthesized or is no longer in the queue
+    pub fn add_repeat(&self, id: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries
+            .iter_mut()
+            .find(|entry| entry.item.id == id && entry.item.state == ItemState::Waiting)
+        {
+            Some(entry) => {
+                entry.item.repeats += 1;
+                self.notify(entries);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes an item, returns false if it was not in the queue
+    pub fn remove(&self, id: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.iter().position(|entry| entry.item.id == id) {
+            Some(index) => {
+                entries.remove(index);
+                self.notify(entries);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every pending item from a user and returns how many were removed
+    pub fn remove_user(&self, user: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|entry| !entry.item.user.eq_ignore_ascii_case(user));
+        let removed = before - entries.len();
+        if removed > 0 {
+            self.notify(entries);
+        }
+        removed
+    }
+
+    /// Moves an item to the front so it is synthesized and played next
+    pub fn move_to_front(&self, id: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.iter().position(|entry| entry.item.id == id) {
+            Some(index) => {
+                entries[index].pinned = Some(self.next_pin.fetch_add(1, Ordering::SeqCst));
+                self.notify(entries);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every item and returns how many were removed
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        self.notify(entries);
+        count
+    }
+
+    /// Waits up to `timeout` for a waiting item and marks it as synthesizing.
+    /// Audio chunks sent on the returned sender can be played while synthesis continues,
+    /// sending fails once the item has been removed or skipped.
+    pub fn next_to_synthesize(
+        &self,
+        timeout: Duration,
+    ) -> Option<(QueueItem, Sender<AudioBuffer>)> {
+        let aging_secs = self.aging_secs();
+        let limits = self.limits();
+        let entries = self.entries.lock().unwrap();
+        let (mut entries, _) = self
+            .changed
+            .wait_timeout_while(entries, timeout, |entries| {
+                !entries
+                    .iter()
+                    .any(|entry| entry.item.state == ItemState::Waiting)
+            })
+            .unwrap();
+        if tidy(&mut entries, aging_secs, &limits) > 0 {
+            self.notify(entries);
+            return None;
+        }
+
+        let entry = entries
+            .iter_mut()
+            .find(|entry| entry.item.state == ItemState::Waiting)?;
+        let (audio_tx, audio_rx) = channel();
+        entry.item.state = ItemState::Synthesizing;
+        entry.audio = Some(audio_rx);
+        let item = entry.item.clone();
+        self.notify(entries);
+        Some((item, audio_tx))
+    }
+
+    /// Marks an item as fully synthesized if it hasn't started playing yet
+    pub fn finish_synthesis(&self, id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.item.id == id) {
+            entry.item.state = ItemState::Ready;
+            self.notify(entries);
+        }
+    }
+
+    /// Puts an item that was being synthesized back into the waiting state
+    pub fn requeue(&self, id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.item.id == id) {
+            entry.item.state = ItemState::Waiting;
+            entry.audio = None;
+            self.notify(entries);
+        }
+    }
+
+    /// Waits up to `timeout` for the front item to have audio and takes it out of the queue.
+    /// The item may still be synthesizing, the receiver disconnects once all chunks are sent.
+    pub fn next_playable(&self, timeout: Duration) -> Option<(QueueItem, Receiver<AudioBuffer>)> {
+        let aging_secs = self.aging_secs();
+        let limits = self.limits();
+        let entries = self.entries.lock().unwrap();
+        let mut dropped = 0;
+        let (mut entries, _) = self
+            .changed
+            .wait_timeout_while(entries, timeout, |entries| {
+                dropped += tidy(entries, aging_secs, &limits);
+                !matches!(entries.front(), Some(entry) if entry.audio.is_some())
+            })
+            .unwrap();
+        if dropped > 0 {
+            self.notify(entries);
+            return None;
+        }
+
+        if !matches!(entries.front(), Some(entry) if entry.audio.is_some()) {
+            return None;
+        }
+        let entry = entries.pop_front().unwrap();
+        self.notify(entries);
+        Some((entry.item, entry.audio.unwrap()))
+    }
+
+    /// Applies the limits, wakes the synth and audio loops and sends the new queue to the UI
+    fn notify(&self, mut entries: MutexGuard<VecDeque<Entry>>) {
+        tidy(&mut entries, self.aging_secs(), &self.limits());
+        let items: Vec<QueueItem> = entries.iter().map(|entry| entry.item.clone()).collect();
+        drop(entries);
+        self.changed.notify_all();
+
+        if let Some(handle) = self.app_handle.lock().unwrap().as_ref() {
+            if let Err(e) = handle.emit(QUEUE_CHANGED_EVENT, items) {
+                println!("Error emitting queue change: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(text: &str, priority: i32, enqueued_at: u64) -> Entry {
+        let mut entry = Entry::new("user", text, MessageKind::Chat, priority);
+        entry.item.enqueued_at = enqueued_at;
+        entry
+    }
+
+    fn limits(max_length: usize, drop_policy: DropPolicy) -> QueueLimits {
+        QueueLimits {
+            max_length,
+            drop_policy,
+            max_age_secs: 0,
+            announce_skipped: false,
+        }
+    }
+
+    fn texts(entries: &VecDeque<Entry>) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.item.text.as_str())
+            .collect()
+    }
+
+    fn queue() -> VecDeque<Entry> {
+        let now = now_millis();
+        VecDeque::from(vec![
+            entry("old", 5, now - 3000),
+            entry("low", 0, now - 2000),
+            entry("new", 5, now - 1000),
+        ])
+    }
+
+    #[test]
+    fn drops_lowest_priority() {
+        let mut entries = queue();
+        let dropped = tidy(&mut entries, 0, &limits(2, DropPolicy::LowestPriority));
+        assert_eq!(dropped, 1);
+        assert_eq!(texts(&entries), ["old", "new"]);
+    }
+
+    #[test]
+    fn drops_oldest() {
+        let mut entries = queue();
+        tidy(&mut entries, 0, &limits(2, DropPolicy::Oldest));
+        assert_eq!(texts(&entries), ["new", "low"]);
+    }
+
+    #[test]
+    fn drops_newest() {
+        let mut entries = queue();
+        tidy(&mut entries, 0, &limits(2, DropPolicy::Newest));
+        assert_eq!(texts(&entries), ["old", "low"]);
+    }
+
+    #[test]
+    fn keeps_started_and_pinned_items() {
+        let mut entries = queue();
+        entries[0].item.state = ItemState::Synthesizing;
+        entries[1].pinned = Some(1);
+        tidy(&mut entries, 0, &limits(1, DropPolicy::Oldest));
+        assert_eq!(texts(&entries), ["old", "low"]);
+    }
+
+    #[test]
+    fn keeps_manual_items() {
+        let now = now_millis();
+        let mut entries = queue();
+        let mut typed = Entry::new("", "typed", MessageKind::Manual, 0);
+        typed.item.enqueued_at = now - 60_000;
+        entries.push_back(typed);
+        let limits = QueueLimits {
+            max_age_secs: 30,
+            ..limits(1, DropPolicy::Oldest)
+        };
+        tidy(&mut entries, 0, &limits);
+        assert_eq!(texts(&entries), ["typed"]);
+    }
+
+    #[test]
+    fn expires_only_waiting_items() {
+        let now = now_millis();
+        let mut entries = VecDeque::from(vec![
+            entry("playing", 0, now - 60_000),
+            entry("stale", 0, now - 60_000),
+            entry("fresh", 0, now),
+        ]);
+        entries[0].item.state = ItemState::Ready;
+        let limits = QueueLimits {
+            max_age_secs: 30,
+            ..limits(0, DropPolicy::Oldest)
+        };
+        assert_eq!(tidy(&mut entries, 0, &limits), 1);
+        assert_eq!(texts(&entries), ["playing", "fresh"]);
+    }
+
+    #[test]
+    fn summarizes_dropped_messages() {
+        let mut entries = queue();
+        let limits = QueueLimits {
+            announce_skipped: true,
+            ..limits(1, DropPolicy::LowestPriority)
+        };
+        assert_eq!(tidy(&mut entries, 0, &limits), 2);
+        assert_eq!(texts(&entries), ["skipped 2 messages", "old"]);
+        // the summary doesn't take the place of a message
+        assert_eq!(tidy(&mut entries, 0, &limits), 0);
+        assert_eq!(texts(&entries), ["skipped 2 messages", "old"]);
+
+        entries.push_back(entry("another", 0, now_millis()));
+        assert_eq!(tidy(&mut entries, 0, &limits), 1);
+        assert_eq!(texts(&entries), ["skipped 3 messages", "old"]);
+    }
+
+    #[test]
+    fn sorting_leaves_started_items_in_front() {
+        let now = now_millis();
+        let mut entries = VecDeque::from(vec![
+            entry("speaking", 0, now),
+            entry("chat", 0, now),
+            entry("raid", 50, now),
+        ]);
+        entries[0].item.state = ItemState::Synthesizing;
+        sort_entries(&mut entries, 0);
+        assert_eq!(texts(&entries), ["speaking", "raid", "chat"]);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::chat::{ChatMessage, MessageKind, UserRole};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limits on how often chatters can use the voice
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Minimum seconds between two messages from the same user, 0 to disable
+    pub user_cooldown_secs: u64,
+    /// Maximum messages one user can have waiting in the queue, 0 for no limit
+    pub max_queued_per_user: usize,
+    /// Maximum messages accepted from all users per minute, 0 for no limit
+    pub messages_per_minute: usize,
+    /// Roles that skip every limit
+    pub exempt_roles: Vec<UserRole>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            user_cooldown_secs: 10,
+            max_queued_per_user: 2,
+            messages_per_minute: 20,
+            exempt_roles: vec![UserRole::Broadcaster, UserRole::Moderator],
+        }
+    }
+}
+
+/// Decides whether a chat message may enter the TTS queue
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// Lowercase names of users who are never read, regardless of role
+    muted_users: HashSet<String>,
+    /// When each user last had a message accepted, keyed by lowercase name
+    last_accepted: HashMap<String, Instant>,
+    /// Acceptance times within the last minute
+    recent: VecDeque<Instant>,
+}
+
+lazy_static! {
+    pub static ref RATE_LIMITER: Mutex<RateLimiter> = Mutex::new(RateLimiter {
+        config: RateLimitConfig::default(),
+        muted_users: HashSet::new(),
+        last_accepted: HashMap::new(),
+        recent: VecDeque::new(),
+    });
+}
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+impl RateLimiter {
+    pub fn set_config(&mut self, config: RateLimitConfig) {
+        self.config = config;
+    }
+
+    pub fn set_muted_users(&mut self, users: &[String]) {
+        self.muted_users = users.iter().map(|user| user.to_lowercase()).collect();
+    }
+
+    /// Records the message if it is allowed, otherwise returns why it was rejected.
+    /// `queued_for_user` is how many messages the sender already has in the queue.
+    pub fn check(&mut self, message: &ChatMessage, queued_for_user: usize) -> Result<(), String> {
+        let user = message.username.to_lowercase();
+        if self.muted_users.contains(&user) {
+            return Err("user is muted".to_string());
+        }
+
+        // raids and subs are one-off events rather than chatter spam
+        if matches!(message.kind, MessageKind::Raid | MessageKind::Subscription)
+            || self.config.exempt_roles.contains(&message.role)
+        {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let cooldown = Duration::from_secs(self.config.user_cooldown_secs);
+        self.last_accepted
+            .retain(|_, accepted| now.duration_since(*accepted) < cooldown);
+        while self
+            .recent
+            .front()
+            .is_some_and(|accepted| now.duration_since(*accepted) >= MINUTE)
+        {
+            self.recent.pop_front();
+        }
+
+        if self.last_accepted.contains_key(&user) {
+            return Err(format!(
+                "user is on a {} second cooldown",
+                self.config.user_cooldown_secs
+            ));
+        }
+        if self.config.max_queued_per_user > 0 && queued_for_user >= self.config.max_queued_per_user
+        {
+            return Err(format!(
+                "user already has {} messages queued",
+                queued_for_user
+            ));
+        }
+        if self.config.messages_per_minute > 0
+            && self.recent.len() >= self.config.messages_per_minute
+        {
+            return Err(format!(
+                "over the limit of {} messages per minute",
+                self.config.messages_per_minute
+            ));
+        }
+
+        if !cooldown.is_zero() {
+            self.last_accepted.insert(user, now);
+        }
+        self.recent.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(user: &str, role: UserRole) -> ChatMessage {
+        ChatMessage {
+            username: user.to_string(),
+            content: "hello".to_string(),
+            role,
+            kind: MessageKind::Chat,
+            bits: 0,
+            reward_id: None,
+            first_message: false,
+            notice: None,
+        }
+    }
+
+    fn limiter(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            muted_users: HashSet::new(),
+            last_accepted: HashMap::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn enforces_the_per_minute_window() {
+        let mut limiter = limiter(RateLimitConfig {
+            user_cooldown_secs: 0,
+            max_queued_per_user: 0,
+            messages_per_minute: 2,
+            exempt_roles: Vec::new(),
+        });
+        assert!(limiter.check(&message("a", UserRole::Viewer), 0).is_ok());
+        assert!(limiter.check(&message("b", UserRole::Viewer), 0).is_ok());
+        assert!(limiter.check(&message("c", UserRole::Viewer), 0).is_err());
+
+        // acceptances older than a minute leave the window
+        let Some(expired) = Instant::now().checked_sub(MINUTE) else {
+            return;
+        };
+        limiter
+            .recent
+            .iter_mut()
+            .for_each(|accepted| *accepted = expired);
+        assert!(limiter.check(&message("c", UserRole::Viewer), 0).is_ok());
+    }
+
+    #[test]
+    fn enforces_user_cooldown_and_queue_limit() {
+        let mut limiter = limiter(RateLimitConfig {
+            messages_per_minute: 0,
+            ..RateLimitConfig::default()
+        });
+        assert!(limiter.check(&message("a", UserRole::Viewer), 0).is_ok());
+        assert!(limiter.check(&message("A", UserRole::Viewer), 0).is_err());
+        assert!(limiter.check(&message("b", UserRole::Viewer), 2).is_err());
+    }
+
+    #[test]
+    fn exempt_roles_and_muted_users() {
+        let mut limiter = limiter(RateLimitConfig::default());
+        limiter.set_muted_users(&["Troll".to_string()]);
+        assert!(limiter
+            .check(&message("troll", UserRole::Moderator), 0)
+            .is_err());
+        for _ in 0..3 {
+            assert!(limiter
+                .check(&message("mod", UserRole::Moderator), 5)
+                .is_ok());
+        }
+    }
+}
@@ -0,0 +1,615 @@
+use crate::audio::AudioBuffer;
+use crate::chat::MessageKind;
+use crate::dsp;
+use lazy_static::lazy_static;
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Recordings are mixed down to one format whatever the sources were
+const RECORDING_SAMPLE_RATE: u32 = 48000;
+const RECORDING_CHANNELS: u16 = 2;
+/// Played audio is handed to the recorder in blocks of about this many samples
+const BLOCK_SAMPLES: usize = 8192;
+/// How far behind real time the file is written, so late blocks can still be mixed in
+const WRITE_DELAY: Duration = Duration::from_secs(2);
+const WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RecorderConfig {
+    /// Records every session from starting the chat reader until it is stopped
+    pub enabled: bool,
+    /// Where recordings are saved, the app data folder when unset
+    pub directory: Option<String>,
+}
+
+/// A message as it appears in the sidecar, times are seconds into the recording
+#[derive(Serialize, Debug, Clone)]
+struct Utterance {
+    start_secs: f64,
+    end_secs: f64,
+    user: String,
+    text: String,
+    /// None for replays of the last message
+    kind: Option<MessageKind>,
+}
+
+#[derive(Serialize, Debug)]
+struct SessionLog {
+    recording: String,
+    /// Seconds since the Unix epoch when the recording started
+    started_at: u64,
+    sample_rate: u32,
+    channels: u16,
+    utterances: Vec<Utterance>,
+}
+
+enum RecorderMessage {
+    /// Audio that played starting at `start`, only speech times the utterances
+    Block {
+        speech: bool,
+        start: Instant,
+        audio: AudioBuffer,
+    },
+    BeginUtterance {
+        user: String,
+        text: String,
+        kind: Option<MessageKind>,
+    },
+    EndUtterance,
+    /// Writes out everything that is left and replies once the files are complete
+    Stop(Sender<Result<(), String>>),
+}
+
+struct Recording {
+    sender: Sender<RecorderMessage>,
+    path: PathBuf,
+}
+
+lazy_static! {
+    static ref RECORDING: Mutex<Option<Recording>> = Mutex::new(None);
+}
+
+/// Changes whenever a recording starts or stops, so sources that were already
+/// playing, like looping music, switch to the new recording
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn current_sender() -> Option<Sender<RecorderMessage>> {
+    RECORDING
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|recording| recording.sender.clone())
+}
+
+/// Passes a source through unchanged, handing a copy of every sample to the
+/// session recorder as the mixer pulls it. The sink applies the volume after the tap,
+/// so the copy is scaled by the volume the sink is playing at, which takes in the bus
+/// volume and mute, the device volume, ducking and fades. Pausing stops the mixer
+/// pulling samples, each block is timed by when its first sample was pulled.
+pub struct Tap<S> {
+    inner: S,
+    speech: bool,
+    channels: u16,
+    sample_rate: u32,
+    sender: Option<Sender<RecorderMessage>>,
+    /// The recording generation `sender` belongs to
+    generation: u64,
+    /// Volume of the tapped sink as f32 bits, kept up to date by the audio output
+    gain: Arc<AtomicU32>,
+    /// When the mixer pulled the first sample of the current block
+    block_started: Option<Instant>,
+    block: Vec<f32>,
+}
+
+impl<S> Tap<S> {
+    fn send_block(&mut self) {
+        let (Some(sender), Some(start)) = (&self.sender, self.block_started.take()) else {
+            return;
+        };
+        if self.block.is_empty() {
+            return;
+        }
+        let block = mem::take(&mut self.block);
+        let _ = sender.send(RecorderMessage::Block {
+            speech: self.speech,
+            start,
+            audio: AudioBuffer::new(block, self.sample_rate, self.channels),
+        });
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Tap<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.inner.next();
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if generation != self.generation {
+            self.send_block();
+            self.generation = generation;
+            self.sender = current_sender();
+        }
+        if self.sender.is_some() {
+            match sample {
+                Some(sample) => {
+                    self.block_started.get_or_insert_with(Instant::now);
+                    let gain = f32::from_bits(self.gain.load(Ordering::Relaxed));
+                    self.block.push(sample * gain);
+                    if self.block.len() >= BLOCK_SAMPLES {
+                        self.send_block();
+                    }
+                }
+                // hand over the end before the sink reports it is empty
+                None => self.send_block(),
+            }
+        }
+        sample
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Tap<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+impl<S> Drop for Tap<S> {
+    fn drop(&mut self) {
+        // a skipped sound is dropped part way through, keep what was played
+        self.send_block();
+    }
+}
+
+/// Wraps a source so it ends up in the recording while one is running.
+/// `gain` follows the volume of the sink the source is played on.
+pub fn tap<S: Source<Item = f32>>(source: S, gain: Arc<AtomicU32>) -> Tap<S> {
+    Tap {
+        speech: false,
+        channels: source.channels(),
+        sample_rate: source.sample_rate(),
+        inner: source,
+        generation: GENERATION.load(Ordering::Relaxed),
+        sender: current_sender(),
+        gain,
+        block_started: None,
+        block: Vec::new(),
+    }
+}
+
+/// Like `tap`, for speech, which sets the start and end of the utterance in the sidecar
+pub fn tap_speech<S: Source<Item = f32>>(source: S, gain: Arc<AtomicU32>) -> Tap<S> {
+    let mut tap = tap(source, gain);
+    tap.speech = true;
+    tap
+}
+
+fn send(message: RecorderMessage) {
+    if let Some(recording) = RECORDING.lock().unwrap().as_ref() {
+        let _ = recording.sender.send(message);
+    }
+}
+
+/// Marks the start of a message in the sidecar, its times come from the audio that follows
+pub fn begin_utterance(user: &str, text: &str, kind: Option<MessageKind>) {
+    send(RecorderMessage::BeginUtterance {
+        user: user.to_string(),
+        text: text.to_string(),
+        kind,
+    });
+}
+
+pub fn end_utterance() {
+    send(RecorderMessage::EndUtterance);
+}
+
+pub fn is_recording() -> bool {
+    RECORDING.lock().unwrap().is_some()
+}
+
+/// Converts days since the Unix epoch to a year, month and day
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// A UTC timestamp that sorts by time and is safe in file names
+fn file_timestamp(unix_secs: u64) -> String {
+    let days = (unix_secs / 86400) as i64;
+    let seconds = unix_secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}_{:02}-{:02}-{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Starts recording into a new timestamped WAV file in `directory`, with a
+/// JSON sidecar of the same name. Returns the path of the WAV file.
+pub fn start(directory: &Path) -> Result<PathBuf, String> {
+    let mut recording = RECORDING.lock().unwrap();
+    if let Some(recording) = recording.as_ref() {
+        return Err(format!("Already recording to {}", recording.path.display()));
+    }
+    fs::create_dir_all(directory)
+        .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let name = format!("tts-session-{}", file_timestamp(started_at));
+    // WAV, the recording is written a block at a time while the session runs
+    let path = directory.join(format!("{}.wav", name));
+    let spec = hound::WavSpec {
+        channels: RECORDING_CHANNELS,
+        sample_rate: RECORDING_SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let writer = hound::WavWriter::create(&path, spec)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let log = SessionLog {
+        recording: format!("{}.wav", name),
+        started_at,
+        sample_rate: RECORDING_SAMPLE_RATE,
+        channels: RECORDING_CHANNELS,
+        utterances: Vec::new(),
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let sidecar = directory.join(format!("{}.json", name));
+    let mut session = Session {
+        started: Instant::now(),
+        writer,
+        written_frames: 0,
+        pending: Vec::new(),
+        log,
+        sidecar,
+        utterance: None,
+    };
+    thread::spawn(move || session.run(receiver));
+
+    println!("Recording session to {}", path.display());
+    *recording = Some(Recording {
+        sender,
+        path: path.clone(),
+    });
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    Ok(path)
+}
+
+/// Stops the recording and waits for the files to be written, returns the WAV path
+pub fn stop() -> Result<PathBuf, String> {
+    let recording = RECORDING
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| "Not recording".to_string())?;
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    let (done_tx, done_rx) = mpsc::channel();
+    recording
+        .sender
+        .send(RecorderMessage::Stop(done_tx))
+        .map_err(|_| "The recorder stopped unexpectedly".to_string())?;
+    done_rx
+        .recv()
+        .map_err(|_| "The recorder stopped unexpectedly".to_string())??;
+    println!("Recording saved to {}", recording.path.display());
+    Ok(recording.path)
+}
+
+/// The recorder thread's state
+struct Session {
+    started: Instant,
+    writer: hound::WavWriter<BufWriter<File>>,
+    written_frames: usize,
+    /// Mixed samples from `written_frames` on that haven't been written yet
+    pending: Vec<f32>,
+    log: SessionLog,
+    sidecar: PathBuf,
+    /// The message being played, if it has produced any audio yet
+    utterance: Option<Utterance>,
+}
+
+impl Session {
+    fn run(&mut self, receiver: Receiver<RecorderMessage>) {
+        let mut last_write = Instant::now();
+        loop {
+            match receiver.recv_timeout(WRITE_INTERVAL) {
+                Ok(RecorderMessage::Block {
+                    speech,
+                    start,
+                    audio,
+                }) => self.mix(speech, start, &audio),
+                Ok(RecorderMessage::BeginUtterance { user, text, kind }) => {
+                    self.utterance = Some(Utterance {
+                        start_secs: f64::MAX,
+                        end_secs: 0.0,
+                        user,
+                        text,
+                        kind,
+                    });
+                }
+                Ok(RecorderMessage::EndUtterance) => self.finish_utterance(),
+                Ok(RecorderMessage::Stop(done)) => {
+                    // blocks sent by sounds that were still playing
+                    while let Ok(message) = receiver.try_recv() {
+                        if let RecorderMessage::Block {
+                            speech,
+                            start,
+                            audio,
+                        } = message
+                        {
+                            self.mix(speech, start, &audio);
+                        }
+                    }
+                    self.finish_utterance();
+                    let _ = done.send(self.finish());
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = self.finish();
+                    return;
+                }
+            }
+            if last_write.elapsed() >= WRITE_INTERVAL {
+                last_write = Instant::now();
+                let elapsed = self.started.elapsed().saturating_sub(WRITE_DELAY);
+                if let Err(e) = self.write_until(frames_in(elapsed)) {
+                    println!("Error writing recording: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Adds played audio to the mix at the time it was played
+    fn mix(&mut self, speech: bool, start: Instant, audio: &AudioBuffer) {
+        let offset = start.saturating_duration_since(self.started);
+        if speech {
+            if let Some(utterance) = self.utterance.as_mut() {
+                let start_secs = offset.as_secs_f64();
+                utterance.start_secs = utterance.start_secs.min(start_secs);
+                utterance.end_secs = utterance
+                    .end_secs
+                    .max(start_secs + audio.duration().as_secs_f64());
+            }
+        }
+
+        let converted = to_recording_format(audio);
+        let frames = converted.len() / RECORDING_CHANNELS as usize;
+        let first_frame = frames_in(offset);
+        for frame in 0..frames {
+            // anything before what was already written arrived too late
+            let Some(index) = (first_frame + frame).checked_sub(self.written_frames) else {
+                continue;
+            };
+            let index = index * RECORDING_CHANNELS as usize;
+            if self.pending.len() < index + RECORDING_CHANNELS as usize {
+                self.pending
+                    .resize(index + RECORDING_CHANNELS as usize, 0.0);
+            }
+            for channel in 0..RECORDING_CHANNELS as usize {
+                self.pending[index + channel] +=
+                    converted[frame * RECORDING_CHANNELS as usize + channel];
+            }
+        }
+    }
+
+    /// Writes the mix up to `frames`, silence fills in where nothing played
+    fn write_until(&mut self, frames: usize) -> Result<(), String> {
+        let count = frames.saturating_sub(self.written_frames) * RECORDING_CHANNELS as usize;
+        if count == 0 {
+            return Ok(());
+        }
+        if self.pending.len() < count {
+            self.pending.resize(count, 0.0);
+        }
+        for sample in self.pending.drain(..count) {
+            self.writer
+                .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+                .map_err(|e| e.to_string())?;
+        }
+        self.written_frames = frames;
+        // keeps the header valid in case the app doesn't get to finish the file
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+
+    fn finish_utterance(&mut self) {
+        let Some(utterance) = self.utterance.take() else {
+            return;
+        };
+        // muted or dropped messages never played
+        if utterance.end_secs <= 0.0 {
+            return;
+        }
+        self.log.utterances.push(utterance);
+        if let Err(e) = self.write_sidecar() {
+            println!("Error writing recording sidecar: {}", e);
+        }
+    }
+
+    fn write_sidecar(&self) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(&self.log).map_err(|e| e.to_string())?;
+        fs::write(&self.sidecar, json)
+            .map_err(|e| format!("Failed to write {}: {}", self.sidecar.display(), e))
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let frames = self.written_frames + self.pending.len() / RECORDING_CHANNELS as usize;
+        let end = frames.max(frames_in(self.started.elapsed()));
+        self.write_until(end)?;
+        self.write_sidecar()
+    }
+}
+
+fn frames_in(duration: Duration) -> usize {
+    (duration.as_secs_f64() * RECORDING_SAMPLE_RATE as f64) as usize
+}
+
+/// Resamples to the recording rate and maps the channels onto stereo
+fn to_recording_format(audio: &AudioBuffer) -> Vec<f32> {
+    let channels = dsp::deinterleave(audio);
+    let frames = channels.first().map_or(0, Vec::len);
+    let len = (frames as f64 * RECORDING_SAMPLE_RATE as f64 / audio.sample_rate.max(1) as f64)
+        .round() as usize;
+    let resampled: Vec<Vec<f32>> = channels
+        .iter()
+        .map(|channel| dsp::resample_to_len(channel, len))
+        .collect();
+    let stereo = match resampled.len() {
+        0 => return Vec::new(),
+        1 => vec![resampled[0].clone(), resampled[0].clone()],
+        _ => resampled[..2].to_vec(),
+    };
+    dsp::interleave(&stereo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+    }
+
+    #[test]
+    fn formats_file_timestamps() {
+        assert_eq!(file_timestamp(0), "1970-01-01_00-00-00");
+        assert_eq!(file_timestamp(1_709_210_096), "2024-02-29_12-34-56");
+    }
+
+    #[test]
+    fn converts_to_the_recording_format() {
+        // mono is copied to both channels
+        let mono = AudioBuffer::new(vec![0.5; 100], RECORDING_SAMPLE_RATE, 1);
+        let converted = to_recording_format(&mono);
+        assert_eq!(converted.len(), 200);
+        assert!(converted.iter().all(|sample| *sample == 0.5));
+
+        // half the rate comes out twice as long, extra channels are dropped
+        let samples = (0..300).map(|i| (i % 3) as f32).collect();
+        let surround = AudioBuffer::new(samples, RECORDING_SAMPLE_RATE / 2, 3);
+        let converted = to_recording_format(&surround);
+        assert_eq!(converted.len(), 400);
+        assert!(converted.chunks(2).all(|frame| frame == [0.0, 1.0]));
+
+        assert!(to_recording_format(&AudioBuffer::new(Vec::new(), 22050, 1)).is_empty());
+    }
+
+    fn session(name: &str) -> (Session, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("recorder-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(format!("{}.wav", name));
+        let spec = hound::WavSpec {
+            channels: RECORDING_CHANNELS,
+            sample_rate: RECORDING_SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let session = Session {
+            started: Instant::now(),
+            writer: hound::WavWriter::create(&path, spec).unwrap(),
+            written_frames: 0,
+            pending: Vec::new(),
+            log: SessionLog {
+                recording: format!("{}.wav", name),
+                started_at: 0,
+                sample_rate: RECORDING_SAMPLE_RATE,
+                channels: RECORDING_CHANNELS,
+                utterances: Vec::new(),
+            },
+            sidecar: directory.join(format!("{}.json", name)),
+            utterance: None,
+        };
+        (session, path)
+    }
+
+    #[test]
+    fn mixes_blocks_at_the_time_they_played() {
+        let (mut session, path) = session("mix");
+        let second = session.started + Duration::from_secs(1);
+        let block = AudioBuffer::new(vec![0.25; 10], RECORDING_SAMPLE_RATE, 1);
+        session.mix(true, second, &block);
+        // overlapping sounds add up
+        session.mix(false, second, &block);
+
+        let start = RECORDING_SAMPLE_RATE as usize * 2;
+        assert_eq!(session.pending.len(), start + 20);
+        assert!(session.pending[..start].iter().all(|sample| *sample == 0.0));
+        assert!(session.pending[start..].iter().all(|sample| *sample == 0.5));
+
+        // blocks from before what was written are too late to mix in
+        session
+            .write_until(frames_in(Duration::from_secs(2)))
+            .unwrap();
+        session.mix(true, second, &block);
+        assert!(session.pending.is_empty());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn times_utterances_by_their_speech() {
+        let (mut session, path) = session("utterance");
+        session.utterance = Some(Utterance {
+            start_secs: f64::MAX,
+            end_secs: 0.0,
+            user: "foo".to_string(),
+            text: "hello".to_string(),
+            kind: Some(MessageKind::Chat),
+        });
+        let speech = AudioBuffer::new(vec![0.1; 4800], RECORDING_SAMPLE_RATE, 1);
+        session.mix(true, session.started + Duration::from_millis(500), &speech);
+        // sounds, like the chime before the speech, don't move the utterance
+        session.mix(false, session.started, &speech);
+        let utterance = session.utterance.as_ref().unwrap();
+        assert!((utterance.start_secs - 0.5).abs() < 1e-9);
+        assert!((utterance.end_secs - 0.6).abs() < 1e-9);
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::alerts::{self, AlertSound};
+use crate::audio::{self, AudioBuffer, Bus};
+use crate::chat::{MessageKind, UserRole};
+use crate::control::PLAYBACK;
+use crate::queue::TTS_QUEUE;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A named sound that can be played from the UI, a hotkey, chat or a redemption
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SoundClip {
+    /// What chat types after `!sound`, matched case-insensitively
+    pub name: String,
+    pub sound: AlertSound,
+    /// Global shortcut that plays the clip
+    #[serde(default)]
+    pub hotkey: Option<String>,
+    /// Channel point reward that plays the clip when redeemed
+    #[serde(default)]
+    pub reward_id: Option<String>,
+    /// Seconds before chat can play the clip again
+    #[serde(default)]
+    pub cooldown_secs: u64,
+    /// Lowest role allowed to play the clip from chat
+    #[serde(default = "default_min_role")]
+    pub min_role: UserRole,
+}
+
+fn default_min_role() -> UserRole {
+    UserRole::Viewer
+}
+
+/// How clips are played while TTS is talking
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipPlayback {
+    /// Play straight away, over any speech
+    Overlap,
+    /// Wait for a turn in the TTS queue like a message, then play on the soundboard bus
+    Queue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SoundboardConfig {
+    pub enabled: bool,
+    pub playback: ClipPlayback,
+    /// Priority of clips in the TTS queue when they are queued
+    pub queue_priority: i32,
+    pub clips: Vec<SoundClip>,
+}
+
+impl Default for SoundboardConfig {
+    fn default() -> Self {
+        SoundboardConfig {
+            enabled: true,
+            playback: ClipPlayback::Overlap,
+            queue_priority: 0,
+            clips: Vec::new(),
+        }
+    }
+}
+
+struct Soundboard {
+    config: SoundboardConfig,
+    /// When each clip, by lowercase name, was last played from chat
+    last_played: HashMap<String, Instant>,
+}
+
+lazy_static! {
+    static ref SOUNDBOARD: Mutex<Soundboard> = Mutex::new(Soundboard {
+        config: SoundboardConfig::default(),
+        last_played: HashMap::new(),
+    });
+}
+
+pub fn set_soundboard_config(config: SoundboardConfig) {
+    SOUNDBOARD.lock().unwrap().config = config;
+}
+
+fn find_clip<'a>(config: &'a SoundboardConfig, name: &str) -> Option<&'a SoundClip> {
+    config
+        .clips
+        .iter()
+        .find(|clip| clip.name.eq_ignore_ascii_case(name))
+}
+
+/// Plays or queues a clip according to the playback setting.
+/// Nothing plays while muted, queued clips are dropped by the audio loop instead.
+fn play_clip(
+    clip: &SoundClip,
+    playback: ClipPlayback,
+    priority: i32,
+    user: &str,
+) -> Result<(), String> {
+    match playback {
+        ClipPlayback::Overlap => {
+            if PLAYBACK.is_muted() {
+                return Err("Sounds are muted".to_string());
+            }
+            let audio = alerts::load_with_volume(&clip.sound)?;
+            audio::output()?.play_on(Bus::Soundboard, &audio);
+            Ok(())
+        }
+        ClipPlayback::Queue => {
+            // check the file now rather than when its turn comes
+            alerts::load_sound(&clip.sound.file)?;
+            TTS_QUEUE.push(user, &clip.name, MessageKind::Sound, priority);
+            Ok(())
+        }
+    }
+}
+
+/// Plays a clip for the streamer, skipping cooldowns and permissions
+pub fn play(name: &str) -> Result<String, String> {
+    let (clip, playback, priority) = {
+        let soundboard = SOUNDBOARD.lock().unwrap();
+        let clip = find_clip(&soundboard.config, name)
+            .ok_or_else(|| format!("There is no sound called {}", name))?
+            .clone();
+        (
+            clip,
+            soundboard.config.playback,
+            soundboard.config.queue_priority,
+        )
+    };
+    play_clip(&clip, playback, priority, "")?;
+    Ok(format!("Playing {}", clip.name))
+}
+
+/// Loads and plays the clip on a blocking thread, decoding and opening the output
+/// would otherwise hold up the chat task
+async fn play_clip_blocking(
+    clip: SoundClip,
+    playback: ClipPlayback,
+    priority: i32,
+    user: &str,
+) -> Result<(), String> {
+    let user = user.to_string();
+    tokio::task::spawn_blocking(move || play_clip(&clip, playback, priority, &user))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Plays a clip requested from chat once the permission and cooldown allow it.
+/// The cooldown only starts once the clip has played.
+pub async fn request(name: &str, user: &str, role: UserRole) -> Result<String, String> {
+    let (clip, playback, priority) = {
+        let soundboard = SOUNDBOARD.lock().unwrap();
+        if !soundboard.config.enabled {
+            return Err("the soundboard is turned off".to_string());
+        }
+        let clip = find_clip(&soundboard.config, name)
+            .ok_or_else(|| format!("there is no sound called {}", name))?
+            .clone();
+        if role < clip.min_role {
+            return Err(format!("you aren't allowed to play {}", clip.name));
+        }
+        let cooldown = Duration::from_secs(clip.cooldown_secs);
+        if let Some(last_played) = soundboard.last_played.get(&clip.name.to_lowercase()) {
+            let elapsed = last_played.elapsed();
+            if elapsed < cooldown {
+                return Err(format!(
+                    "{} is on cooldown for {}s",
+                    clip.name,
+                    (cooldown - elapsed).as_secs() + 1
+                ));
+            }
+        }
+        (
+            clip,
+            soundboard.config.playback,
+            soundboard.config.queue_priority,
+        )
+    };
+
+    let key = clip.name.to_lowercase();
+    let reply = format!("playing {}", clip.name);
+    play_clip_blocking(clip, playback, priority, user).await?;
+    SOUNDBOARD
+        .lock()
+        .unwrap()
+        .last_played
+        .insert(key, Instant::now());
+    Ok(reply)
+}
+
+/// Plays the clip tied to a channel point reward, Twitch already enforces its cooldown.
+/// Returns whether a clip was played, in which case it replaces the redemption alert.
+pub async fn on_redemption(reward_id: &str, user: &str) -> bool {
+    let (clip, playback, priority) = {
+        let soundboard = SOUNDBOARD.lock().unwrap();
+        if !soundboard.config.enabled {
+            return false;
+        }
+        let clip = soundboard
+            .config
+            .clips
+            .iter()
+            .find(|clip| clip.reward_id.as_deref() == Some(reward_id));
+        match clip {
+            Some(clip) => (
+                clip.clone(),
+                soundboard.config.playback,
+                soundboard.config.queue_priority,
+            ),
+            None => return false,
+        }
+    };
+    println!("Playing {} for {}'s redemption", clip.name, user);
+    match play_clip_blocking(clip, playback, priority, user).await {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Error playing sound: {}", e);
+            false
+        }
+    }
+}
+
+/// The audio of a queued clip, at its configured volume
+pub fn clip_audio(name: &str) -> Result<AudioBuffer, String> {
+    let clip = {
+        let soundboard = SOUNDBOARD.lock().unwrap();
+        find_clip(&soundboard.config, name)
+            .ok_or_else(|| format!("There is no sound called {}", name))?
+            .clone()
+    };
+    alerts::load_with_volume(&clip.sound)
+}
@@ -0,0 +1,316 @@
+use crate::chat::{ChatMessage, MessageKind, UserRole};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Only the start of a message is compared, which keeps the similarity check cheap
+/// on the chat task however long messages get. Copypasta is alike from the start.
+const MAX_COMPARED_CHARS: usize = 200;
+
+/// How repeated messages and copypasta are handled
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SpamConfig {
+    pub enabled: bool,
+    /// How long a message is remembered for duplicate checks
+    pub window_secs: u64,
+    /// Drop messages a user already sent within the window
+    pub block_user_repeats: bool,
+    /// How many different users can send the same message before copies are dropped
+    pub max_copies: usize,
+    /// 0.0 to 1.0, how similar two messages must be to count as the same.
+    /// 1.0 only matches identical messages
+    pub similarity_threshold: f32,
+    /// Read dropped copies as "and N others said the same" on the queued message
+    pub collapse_copies: bool,
+    pub exempt_roles: Vec<UserRole>,
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        SpamConfig {
+            enabled: true,
+            window_secs: 60,
+            block_user_repeats: true,
+            max_copies: 1,
+            similarity_threshold: 0.85,
+            collapse_copies: true,
+            exempt_roles: vec![UserRole::Broadcaster],
+        }
+    }
+}
+
+/// Messages that matched each other within the window
+struct MessageGroup {
+    normalized: String,
+    /// Length of the normalized text in characters
+    length: usize,
+    /// Lowercase names of everyone who sent this message
+    users: HashSet<String>,
+    /// The queue item that reads this message, if one was queued
+    queue_id: Option<String>,
+    last_seen: Instant,
+}
+
+/// What `check` found, recorded by `attach` once the message is queued
+pub struct SpamMatch {
+    /// The group of earlier copies, a new group is started when there is none
+    group: Option<u64>,
+    normalized: String,
+    user: String,
+}
+
+/// Why a message was dropped
+pub struct SpamRejection {
+    pub reason: String,
+    /// The queued item the message was counted as a repeat of
+    pub repeat_of: Option<String>,
+}
+
+pub struct SpamFilter {
+    config: SpamConfig,
+    groups: HashMap<u64, MessageGroup>,
+    next_group: u64,
+}
+
+lazy_static! {
+    pub static ref SPAM_FILTER: Mutex<SpamFilter> = Mutex::new(SpamFilter {
+        config: SpamConfig::default(),
+        groups: HashMap::new(),
+        next_group: 0,
+    });
+}
+
+/// Lowercases, strips punctuation and collapses repeated words,
+/// so "LUL LUL LUL!!" and "lul lul" compare equal. Cut to `MAX_COMPARED_CHARS`.
+fn normalize(text: &str) -> String {
+    let cleaned: String = text
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    let mut words: Vec<&str> = cleaned.split_whitespace().collect();
+    words.dedup();
+    let normalized = if words.is_empty() {
+        // messages made only of symbols still need something to compare
+        text.trim().to_lowercase()
+    } else {
+        words.join(" ")
+    };
+    normalized.chars().take(MAX_COMPARED_CHARS).collect()
+}
+
+/// Levenshtein similarity between 0.0 (nothing in common) and 1.0 (identical)
+fn similarity(a: &str, b: &str) -> f32 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    1.0 - previous[b.len()] as f32 / longest as f32
+}
+
+impl SpamFilter {
+    pub fn set_config(&mut self, config: SpamConfig) {
+        self.config = config;
+    }
+
+    fn find_group(&self, normalized: &str) -> Option<u64> {
+        let threshold = self.config.similarity_threshold;
+        let length = normalized.chars().count();
+        let mut best: Option<(u64, f32)> = None;
+        for (key, group) in &self.groups {
+            if group.normalized == normalized {
+                return Some(*key);
+            }
+            if threshold >= 1.0 {
+                continue;
+            }
+            // the edit distance is at least the length difference, skip hopeless pairs
+            let shorter = group.length.min(length) as f32;
+            let longer = group.length.max(length) as f32;
+            if shorter / longer < threshold {
+                continue;
+            }
+            let score = similarity(&group.normalized, normalized);
+            let better = match best {
+                Some((_, best_score)) => score > best_score,
+                None => true,
+            };
+            if score >= threshold && better {
+                best = Some((*key, score));
+            }
+        }
+        best.map(|(key, _)| key)
+    }
+
+    /// Checks a message against recently seen ones.
+    /// Returns a match to pass to `attach` once the message is queued, or why it was dropped.
+    /// Only queued messages are remembered, so a message dropped later on, for example
+    /// by the rate limits, doesn't count as a repeat when it is sent again.
+    pub fn check(&mut self, message: &ChatMessage) -> Result<SpamMatch, SpamRejection> {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_secs);
+        self.groups
+            .retain(|_, group| now.duration_since(group.last_seen) < window);
+
+        let normalized = normalize(&message.content);
+        let user = message.username.to_lowercase();
+        let exempt = !self.config.enabled
+            || matches!(message.kind, MessageKind::Raid | MessageKind::Subscription)
+            || self.config.exempt_roles.contains(&message.role);
+        let key = if exempt {
+            None
+        } else {
+            self.find_group(&normalized)
+        };
+        let Some(key) = key else {
+            return Ok(SpamMatch {
+                group: None,
+                normalized,
+                user,
+            });
+        };
+
+        let group = self.groups.get_mut(&key).unwrap();
+        group.last_seen = now;
+        if group.users.contains(&user) {
+            if self.config.block_user_repeats {
+                return Err(SpamRejection {
+                    reason: "user repeated their message".to_string(),
+                    repeat_of: None,
+                });
+            }
+        } else if group.users.len() >= self.config.max_copies.max(1) {
+            let copies = group.users.len();
+            let mut repeat_of = None;
+            if self.config.collapse_copies && group.queue_id.is_some() {
+                // the copy is read as part of the queued message, so remember who sent it
+                group.users.insert(user);
+                repeat_of = group.queue_id.clone();
+            }
+            return Err(SpamRejection {
+                reason: format!("{} others already sent this message", copies),
+                repeat_of,
+            });
+        }
+        Ok(SpamMatch {
+            group: Some(key),
+            normalized,
+            user,
+        })
+    }
+
+    /// Remembers a checked message once it has been queued, linking it to the item that reads it
+    pub fn attach(&mut self, matched: SpamMatch, queue_id: &str) {
+        if let Some(group) = matched.group.and_then(|key| self.groups.get_mut(&key)) {
+            group.users.insert(matched.user);
+            if group.queue_id.is_none() {
+                group.queue_id = Some(queue_id.to_string());
+            }
+            return;
+        }
+        let key = self.next_group;
+        self.next_group += 1;
+        self.groups.insert(
+            key,
+            MessageGroup {
+                length: matched.normalized.chars().count(),
+                normalized: matched.normalized,
+                users: HashSet::from([matched.user]),
+                queue_id: Some(queue_id.to_string()),
+                last_seen: Instant::now(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(user: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            username: user.to_string(),
+            content: content.to_string(),
+            kind: MessageKind::Chat,
+            role: UserRole::Viewer,
+            bits: 0,
+            reward_id: None,
+            first_message: false,
+            notice: None,
+        }
+    }
+
+    fn filter() -> SpamFilter {
+        SpamFilter {
+            config: SpamConfig::default(),
+            groups: HashMap::new(),
+            next_group: 0,
+        }
+    }
+
+    #[test]
+    fn normalizes_case_punctuation_and_repeats() {
+        assert_eq!(normalize("LUL LUL LUL!!"), "lul");
+        assert_eq!(normalize("Hello,   World"), "hello world");
+        assert_eq!(normalize("?!?"), "?!?");
+        let long = "word ".repeat(500) + "end";
+        assert_eq!(normalize(&long), "word end");
+        let long: String = (0..500).map(|i| format!("w{} ", i)).collect();
+        assert_eq!(normalize(&long).chars().count(), MAX_COMPARED_CHARS);
+    }
+
+    #[test]
+    fn similarity_scores() {
+        assert_eq!(similarity("", ""), 1.0);
+        assert_eq!(similarity("abc", "abc"), 1.0);
+        assert_eq!(similarity("abc", "xyz"), 0.0);
+        assert!((similarity("kitten", "sitting") - (1.0 - 3.0 / 7.0)).abs() < 1e-6);
+        // counted in characters, not bytes
+        assert_eq!(similarity("héllo", "hallo"), 0.8);
+    }
+
+    #[test]
+    fn drops_copies_of_queued_messages() {
+        let mut filter = filter();
+        let first = filter.check(&message("a", "first!")).ok().unwrap();
+        filter.attach(first, "id");
+
+        let repeat = filter.check(&message("a", "FIRST")).err().unwrap();
+        assert_eq!(repeat.repeat_of, None);
+        let copy = filter.check(&message("b", "first")).err().unwrap();
+        assert_eq!(copy.repeat_of.as_deref(), Some("id"));
+    }
+
+    #[test]
+    fn forgets_messages_that_were_not_queued() {
+        let mut filter = filter();
+        assert!(filter.check(&message("a", "hello")).is_ok());
+        assert!(filter.check(&message("a", "hello")).is_ok());
+    }
+
+    #[test]
+    fn exempt_roles_skip_the_filter() {
+        let mut filter = filter();
+        let first = filter.check(&message("a", "hello")).ok().unwrap();
+        filter.attach(first, "id");
+        let mut broadcaster = message("a", "hello");
+        broadcaster.role = UserRole::Broadcaster;
+        assert!(filter.check(&broadcaster).is_ok());
+    }
+}
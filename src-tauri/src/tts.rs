@@ -1,15 +1,29 @@
 use anyhow::Result;
+use lazy_static::lazy_static;
 use piper_rs::synth::PiperSpeechSynthesizer;
-use rodio::buffer::SamplesBuffer;
+use piper_rs::PiperModel;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
 use std::path::Path;
 // use rodio::SamplesBuffer;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tauri::AppHandle;
 
+use crate::alerts;
+use crate::audio::{self, AudioBuffer, Bus};
+use crate::chat::MessageKind;
+use crate::control::PLAYBACK;
+use crate::dsp;
+use crate::effects;
+use crate::queue::{QueueItem, TTS_QUEUE};
+use crate::recorder;
+use crate::soundboard;
+
 /// Gets all available speakers from the Piper model
 /// Returns a sorted Vec of (id, name) tuples
 pub fn get_available_speakers(resources_dir: &PathBuf) -> Result<Vec<(i32, String)>, String> {
@@ -30,9 +44,329 @@ pub fn get_available_speakers(resources_dir: &PathBuf) -> Result<Vec<(i32, Strin
     Ok(speakers)
 }
 
+/// Checks the model has a speaker by reading the speaker map from its config,
+/// without loading the model itself. Models with a single voice have no map.
+pub fn has_speaker(resources_dir: &Path, speaker_id: i64) -> Result<bool, String> {
+    let config_path = resources_dir.join("model.onnx.json");
+    let contents = std::fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read {}: {}", config_path.display(), e))?;
+    let config: serde_json::Value = serde_json::from_str(&contents)
+        .map_err(|e| format!("Failed to parse {}: {}", config_path.display(), e))?;
+    let Some(speakers) = config["speaker_id_map"]
+        .as_object()
+        .filter(|map| !map.is_empty())
+    else {
+        return Ok(true);
+    };
+    Ok(speakers.values().any(|id| id.as_i64() == Some(speaker_id)))
+}
+
+/// The sample rate and channel count the model produces, Piper voices come in
+/// several quality levels that don't share a sample rate
+pub fn output_format(model: &Arc<dyn PiperModel + Send + Sync>) -> (u32, u16) {
+    match model.audio_output_info() {
+        Ok(info) => (info.sample_rate as u32, info.num_channels as u16),
+        Err(e) => {
+            println!(
+                "Error reading model audio format, assuming 22050 Hz mono: {}",
+                e
+            );
+            (22050, 1)
+        }
+    }
+}
+
+/// How every message sounds, applied from the next message on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SpeechConfig {
+    /// Piper's length scale multiplies the duration of every phoneme, so a lower value speaks faster
+    pub length_scale: f32,
+    /// Variation in the voice, the model's own value when unset
+    pub noise_scale: Option<f32>,
+    /// Variation in phoneme length, the model's own value when unset
+    pub noise_w: Option<f32>,
+    /// Semitones to shift the pitch by after synthesis, negative is lower
+    pub pitch_semitones: f32,
+    /// Volume for individual speaker ids, voices that aren't listed play at 1.0
+    pub voice_volumes: HashMap<i64, f32>,
+    /// Stereo position for speaker ids, -1.0 is hard left and 1.0 hard right
+    pub voice_pans: HashMap<i64, f32>,
+    /// Stereo position for lowercase usernames, wins over the voice
+    pub user_pans: HashMap<String, f32>,
+    /// Spreads users without a position over this much of each side, 0 leaves them centered
+    pub auto_pan_width: f32,
+}
+
+impl Default for SpeechConfig {
+    fn default() -> Self {
+        SpeechConfig {
+            length_scale: 1.0,
+            noise_scale: None,
+            noise_w: None,
+            pitch_semitones: 0.0,
+            voice_volumes: HashMap::new(),
+            voice_pans: HashMap::new(),
+            user_pans: HashMap::new(),
+            auto_pan_width: 0.0,
+        }
+    }
+}
+
+impl SpeechConfig {
+    pub fn voice_volume(&self, speaker: i64) -> f32 {
+        self.voice_volumes
+            .get(&speaker)
+            .copied()
+            .unwrap_or(1.0)
+            .max(0.0)
+    }
+
+    /// Where to place a message in the stereo field, None plays it in mono
+    pub fn pan_for(&self, user: &str, speaker: i64) -> Option<f32> {
+        let user = user.to_lowercase();
+        if let Some(pan) = self
+            .user_pans
+            .get(&user)
+            .or_else(|| self.voice_pans.get(&speaker))
+        {
+            return Some(*pan);
+        }
+        if self.auto_pan_width <= 0.0 || user.is_empty() {
+            return None;
+        }
+        // the same user always lands in the same spot
+        let hash = user.bytes().fold(0u32, |hash, byte| {
+            hash.wrapping_mul(31).wrapping_add(byte as u32)
+        });
+        let position = (hash % 1001) as f32 / 500.0 - 1.0;
+        Some(position * self.auto_pan_width.min(1.0))
+    }
+}
+
+/// Keeps a single message from occupying the voice for too long
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LengthLimits {
+    /// Longer messages are cut at a word boundary, 0 disables it
+    pub max_characters: usize,
+    /// Messages are faded out once they have played this long, 0 disables it
+    pub max_duration_secs: f32,
+    pub fade_out_ms: u64,
+}
+
+impl Default for LengthLimits {
+    fn default() -> Self {
+        LengthLimits {
+            max_characters: 300,
+            max_duration_secs: 30.0,
+            fade_out_ms: 1000,
+        }
+    }
+}
+
+impl LengthLimits {
+    fn max_duration(&self) -> Option<Duration> {
+        if self.max_duration_secs > 0.0 {
+            Some(Duration::from_secs_f32(self.max_duration_secs))
+        } else {
+            None
+        }
+    }
+
+    fn fade_out(&self) -> Duration {
+        Duration::from_millis(self.fade_out_ms)
+    }
+}
+
+/// Cuts text to at most `max_characters`, at the last word boundary when there is one
+fn truncate_text(text: &str, max_characters: usize) -> String {
+    if max_characters == 0 || text.chars().count() <= max_characters {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max_characters).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(boundary) if boundary > 0 => &cut[..boundary],
+        _ => &cut,
+    };
+    let cut = cut.trim_end_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation());
+    format!("{}. message truncated", cut)
+}
+
+/// Speeds up speech when the queue falls behind instead of dropping messages.
+/// The scales below multiply the configured `SpeechConfig::length_scale`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AdaptiveRateConfig {
+    pub enabled: bool,
+    /// Length scale multiplier used when the queue is keeping up
+    pub max_length_scale: f32,
+    /// Fastest length scale multiplier used when the queue is far behind
+    pub min_length_scale: f32,
+    /// Start speeding up once this many messages are waiting
+    pub start_queue_length: usize,
+    /// Reach the fastest rate at this many waiting messages
+    pub full_queue_length: usize,
+    /// Start speeding up once the oldest message has waited this long
+    pub start_age_secs: u64,
+    /// Reach the fastest rate once the oldest message has waited this long
+    pub full_age_secs: u64,
+}
+
+impl Default for AdaptiveRateConfig {
+    fn default() -> Self {
+        AdaptiveRateConfig {
+            enabled: true,
+            max_length_scale: 1.0,
+            min_length_scale: 0.6,
+            start_queue_length: 3,
+            full_queue_length: 15,
+            start_age_secs: 20,
+            full_age_secs: 90,
+        }
+    }
+}
+
+impl AdaptiveRateConfig {
+    /// Picks a length scale multiplier for the current backlog
+    pub fn length_scale(&self, queue_length: usize, oldest_age: Duration) -> f32 {
+        if !self.enabled {
+            return self.max_length_scale;
+        }
+        // 0.0 when keeping up, 1.0 when we should talk as fast as allowed
+        let pressure = |value: f32, start: f32, full: f32| {
+            if full <= start {
+                return if value >= start { 1.0 } else { 0.0 };
+            }
+            ((value - start) / (full - start)).clamp(0.0, 1.0)
+        };
+        let queue_pressure = pressure(
+            queue_length as f32,
+            self.start_queue_length as f32,
+            self.full_queue_length as f32,
+        );
+        let age_pressure = pressure(
+            oldest_age.as_secs_f32(),
+            self.start_age_secs as f32,
+            self.full_age_secs as f32,
+        );
+        let pressure = queue_pressure.max(age_pressure);
+        self.max_length_scale - (self.max_length_scale - self.min_length_scale) * pressure
+    }
+}
+
+lazy_static! {
+    static ref ADAPTIVE_RATE: Mutex<AdaptiveRateConfig> = Mutex::new(AdaptiveRateConfig::default());
+    static ref CURRENT_LENGTH_SCALE: Mutex<f32> = Mutex::new(1.0);
+    static ref SPEECH: Mutex<SpeechConfig> = Mutex::new(SpeechConfig::default());
+    static ref LENGTH_LIMITS: Mutex<LengthLimits> = Mutex::new(LengthLimits::default());
+}
+
+/// The speaker the synth loop should use, picked up before the next message
+static SELECTED_SPEAKER: AtomicI64 = AtomicI64::new(0);
+
+pub fn set_speaker(speaker_id: i64) {
+    SELECTED_SPEAKER.store(speaker_id, Ordering::SeqCst);
+}
+
+pub fn set_adaptive_rate_config(config: AdaptiveRateConfig) {
+    *ADAPTIVE_RATE.lock().unwrap() = config;
+}
+
+pub fn set_speech_config(config: SpeechConfig) {
+    *SPEECH.lock().unwrap() = config;
+}
+
+pub fn set_length_limits(limits: LengthLimits) {
+    *LENGTH_LIMITS.lock().unwrap() = limits;
+}
+
+/// The length scale used for the most recent message
+pub fn current_length_scale() -> f32 {
+    *CURRENT_LENGTH_SCALE.lock().unwrap()
+}
+
+/// Piper parameters applied to the model, compared to skip needless updates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SynthesisParams {
+    pub length_scale: f32,
+    pub noise_scale: Option<f32>,
+    pub noise_w: Option<f32>,
+}
+
+/// The noise settings a model was loaded with, used again when an override is cleared
+#[derive(Debug, Clone, Copy)]
+pub struct NoiseDefaults {
+    noise_scale: f32,
+    noise_w: f32,
+}
+
+fn synthesis_config(
+    model: &Arc<dyn PiperModel + Send + Sync>,
+) -> Result<Box<piper_rs::SynthesisConfig>, String> {
+    model
+        .get_fallback_synthesis_config()
+        .map_err(|e| e.to_string())?
+        .downcast::<piper_rs::SynthesisConfig>()
+        .map_err(|_| String::from("Model does not use a Piper synthesis config"))
+}
+
+/// Reads the model's own noise settings, call it before changing any parameters
+pub fn noise_defaults(model: &Arc<dyn PiperModel + Send + Sync>) -> Result<NoiseDefaults, String> {
+    let synthesis_config = synthesis_config(model)?;
+    Ok(NoiseDefaults {
+        noise_scale: synthesis_config.noise_scale,
+        noise_w: synthesis_config.noise_w,
+    })
+}
+
+/// Changes the parameters Piper uses for the following synthesis calls
+pub fn set_synthesis_params(
+    model: &Arc<dyn PiperModel + Send + Sync>,
+    params: SynthesisParams,
+    defaults: NoiseDefaults,
+) -> Result<(), String> {
+    let mut synthesis_config = synthesis_config(model)?;
+    synthesis_config.length_scale = params.length_scale;
+    synthesis_config.noise_scale = params.noise_scale.unwrap_or(defaults.noise_scale);
+    synthesis_config.noise_w = params.noise_w.unwrap_or(defaults.noise_w);
+    model
+        .set_fallback_synthesis_config(synthesis_config.as_ref())
+        .map_err(|e| e.to_string())
+}
+
+/// Turns a queue item into the sentence that gets spoken
+fn spoken_text(item: &QueueItem, max_characters: usize) -> String {
+    let text = match item.kind {
+        // Twitch's description already reads as a sentence, e.g. "Foo is raiding with
+        // 12 viewers", only the message the user added to it is cut
+        MessageKind::Raid | MessageKind::Subscription => {
+            let notice = item.notice.as_deref().unwrap_or_default();
+            let own = item.text.strip_prefix(notice).unwrap_or(&item.text).trim();
+            let own = truncate_text(own, max_characters);
+            match (notice.is_empty(), own.is_empty()) {
+                (_, true) => notice.to_string(),
+                (true, false) => own,
+                (false, false) => format!("{} {}", notice, own),
+            }
+        }
+        MessageKind::System => item.text.clone(),
+        // the streamer's own text is read as typed
+        MessageKind::Manual => item.text.clone(),
+        _ => format!(
+            "user {} said {}",
+            item.user,
+            truncate_text(&item.text, max_characters)
+        ),
+    };
+    match item.repeats {
+        0 => text,
+        1 => format!("{}, and 1 other said the same", text),
+        repeats => format!("{}, and {} others said the same", text, repeats),
+    }
+}
+
 pub async fn synth_loop(
-    tts_rx: Receiver<String>,
-    audio_tx: &Sender<Vec<f32>>,
     kill_flag: &Arc<AtomicBool>,
     resources_dir: &PathBuf,
     app_handle: AppHandle,
@@ -55,50 +389,150 @@ pub async fn synth_loop(
 
     // Get selected speaker from config
     let config = crate::load_config(&app_handle);
-    model.set_speaker(config.selected_speaker_id as i64);
+    let mut applied_speaker = config.selected_speaker_id as i64;
+    model.set_speaker(applied_speaker);
+    set_speaker(applied_speaker);
+
+    let (sample_rate, channels) = output_format(&model);
+    println!("Model outputs {} Hz, {} channel(s)", sample_rate, channels);
 
-    let synth = PiperSpeechSynthesizer::new(model)
+    let synth = PiperSpeechSynthesizer::new(model.clone())
         .map_err(|e| e.to_string())
         .unwrap();
     println!("tts model initialized");
+    // a fresh model starts with its own default parameters
+    let mut applied_params: Option<SynthesisParams> = None;
+    let defaults = noise_defaults(&model);
     loop {
         if kill_flag.load(Ordering::SeqCst) {
             println!("Kill signal received, stopping synthesizer loop...");
             break;
         }
-        let text = tts_rx.recv().unwrap();
-        println!("Synthesizing: {}", text);
-        let text = text.to_string();
-
-        // synthesize the text to speech
-        let mut samples: Vec<f32> = Vec::new();
-        let audio = match synth.synthesize_parallel(text, None) {
-            Ok(audio) => {
-                println!("Successfully synthesized audio");
-                audio
+        let (item, audio_tx) = match TTS_QUEUE.next_to_synthesize(Duration::from_millis(50)) {
+            Some(next) => next,
+            None => continue,
+        };
+        // queued soundboard clips are ready as they are
+        if item.kind == MessageKind::Sound {
+            match soundboard::clip_audio(&item.text) {
+                Ok(clip) => {
+                    let _ = audio_tx.send(clip);
+                    drop(audio_tx);
+                    TTS_QUEUE.finish_synthesis(&item.id);
+                }
+                Err(e) => {
+                    println!("Error loading sound {}: {}", item.text, e);
+                    TTS_QUEUE.remove(&item.id);
+                }
             }
+            continue;
+        }
+        let limits = LENGTH_LIMITS.lock().unwrap().clone();
+        let text = spoken_text(&item, limits.max_characters);
+
+        // the voice can be changed from the UI or chat while we are running
+        let speaker = SELECTED_SPEAKER.load(Ordering::SeqCst);
+        if speaker != applied_speaker {
+            println!("Switching to speaker {}", speaker);
+            model.set_speaker(speaker);
+            applied_speaker = speaker;
+        }
+
+        // speed up when the queue is falling behind
+        let speech = SPEECH.lock().unwrap().clone();
+        let (queue_length, oldest_age) = TTS_QUEUE.backlog();
+        let rate = ADAPTIVE_RATE
+            .lock()
+            .unwrap()
+            .length_scale(queue_length, oldest_age);
+        let params = SynthesisParams {
+            length_scale: speech.length_scale * rate,
+            noise_scale: speech.noise_scale,
+            noise_w: speech.noise_w,
+        };
+        if applied_params != Some(params) {
+            let applied = match &defaults {
+                Ok(defaults) => set_synthesis_params(&model, params, *defaults),
+                Err(e) => Err(e.clone()),
+            };
+            match applied {
+                Ok(()) => {
+                    println!("Synthesis parameters set to {:?}", params);
+                    applied_params = Some(params);
+                    *CURRENT_LENGTH_SCALE.lock().unwrap() = params.length_scale;
+                }
+                Err(e) => println!("Error setting synthesis parameters: {}", e),
+            }
+        }
+        let volume = speech.voice_volume(speaker);
+        let pan = speech.pan_for(&item.user, speaker);
+        let effects = effects::effects_for(&item, speaker);
+        let mut processor = dsp::Processor::new(speech.pitch_semitones, effects, volume, pan);
+        println!("Synthesizing: {}", text);
+
+        // synthesize one sentence at a time so playback can start on the first chunk
+        let audio = match synth.synthesize_lazy(text, None) {
+            Ok(audio) => audio,
             Err(e) => {
                 println!("Error synthesizing: {}", e);
-                break;
+                TTS_QUEUE.remove(&item.id);
+                continue;
             }
         };
+        let mut cancelled = false;
+        let mut synthesized = Duration::ZERO;
         for result in audio {
-            samples.append(&mut result.unwrap().into_vec());
-            println!("samples: {:?}", samples.len());
+            if kill_flag.load(Ordering::SeqCst) {
+                break;
+            }
+            let chunk = match result {
+                Ok(chunk) => AudioBuffer::new(chunk.into_vec(), sample_rate, channels),
+                Err(e) => {
+                    println!("Error synthesizing chunk: {}", e);
+                    continue;
+                }
+            };
+            let chunk = processor.process(&chunk);
+            if chunk.samples.is_empty() {
+                continue;
+            }
+            // the receiver is dropped when the item is removed or skipped
+            synthesized += chunk.duration();
+            if audio_tx.send(chunk).is_err() {
+                println!("Item {} was removed while synthesizing, stopping", item.id);
+                cancelled = true;
+                break;
+            }
+            // anything past the maximum duration would be cut off by the audio loop anyway
+            if let Some(max_duration) = limits.max_duration() {
+                if synthesized >= max_duration + limits.fade_out() {
+                    println!("Item {} reached the maximum duration", item.id);
+                    break;
+                }
+            }
+        }
+        if !cancelled && !kill_flag.load(Ordering::SeqCst) {
+            // the pause kept after the last sentence
+            if let Some(tail) = processor.finish() {
+                let _ = audio_tx.send(tail);
+            }
         }
+        drop(audio_tx);
         if kill_flag.load(Ordering::SeqCst) {
             println!("Kill signal received, stopping synthesizer loop...");
+            TTS_QUEUE.requeue(&item.id);
             break;
         }
-        println!("Sending audio to audio_tx");
-
-        audio_tx.send(samples).unwrap();
+        if !cancelled {
+            println!("Successfully synthesized audio");
+            TTS_QUEUE.finish_synthesis(&item.id);
+        }
     }
 
     Ok(())
 }
 
-pub async fn audio_loop(audio_rx: Receiver<Vec<f32>>, kill_flag: &Arc<AtomicBool>) -> Result<()> {
+pub async fn audio_loop(kill_flag: &Arc<AtomicBool>) -> Result<()> {
     println!("Starting audio loop");
     loop {
         if kill_flag.load(Ordering::SeqCst) {
@@ -106,25 +540,292 @@ pub async fn audio_loop(audio_rx: Receiver<Vec<f32>>, kill_flag: &Arc<AtomicBool
             break;
         }
 
-        let samples = audio_rx.recv().unwrap();
+        // replays take priority over new messages, otherwise wait briefly so
+        // the kill flag and control requests are still checked while idle
+        let (chunks, chime, item) = match PLAYBACK.take_replay() {
+            Some(audio) => {
+                println!("Replaying last message");
+                let (replay_tx, replay_rx) = mpsc::channel();
+                let _ = replay_tx.send(audio);
+                (replay_rx, None, None)
+            }
+            None => match TTS_QUEUE.next_playable(Duration::from_millis(50)) {
+                Some((item, chunks)) if item.kind == MessageKind::Sound => {
+                    println!("Playing sound {}", item.text);
+                    (chunks, None, Some(item))
+                }
+                Some((item, chunks)) => {
+                    println!("Playing message from {}", item.user);
+                    (chunks, alerts::message_chime(), Some(item))
+                }
+                None => continue,
+            },
+        };
 
-        // play the audio
-        println!("Playing audio");
-        let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
-        let sink = rodio::Sink::try_new(&handle).unwrap();
-        let buf = SamplesBuffer::new(1, 22050, samples);
-        sink.append(buf);
+        if PLAYBACK.is_muted() {
+            // dropping the chunks also stops the synth loop working on this message
+            println!("TTS muted, dropping message");
+            continue;
+        }
+        // a skip requested while nothing was playing shouldn't skip this message
+        PLAYBACK.take_skip();
+        if let Some(item) = &item {
+            alerts::on_read(item);
+        }
 
-        while !sink.empty() {
-            if kill_flag.load(Ordering::SeqCst) {
-                sink.stop();
-                println!("Kill signal received, stopping audio loop...");
-                break;
+        match &item {
+            Some(item) => recorder::begin_utterance(&item.user, &item.text, Some(item.kind)),
+            None => recorder::begin_utterance("", "replay of the last message", None),
+        }
+        let played = match &item {
+            Some(item) if item.kind == MessageKind::Sound => {
+                play_queued_clip(kill_flag, chunks).map(|()| None)
             }
-            std::thread::sleep(std::time::Duration::from_millis(50));
+            _ => play_chunks(kill_flag, chunks, chime),
+        };
+        match played {
+            Ok(Some(played)) => PLAYBACK.set_last_played(played),
+            Ok(None) => {}
+            Err(e) => println!("Error playing message: {}", e),
         }
+        recorder::end_utterance();
 
         println!("Thread finished synthesizing and playing");
     }
     Ok(())
 }
+
+/// Plays a queued soundboard clip on the soundboard bus and waits for it to end, so the
+/// next message still waits its turn. Skipping only stops the wait, like any other clip
+/// the sound itself isn't stopped by skipping or pausing speech.
+fn play_queued_clip(
+    kill_flag: &Arc<AtomicBool>,
+    chunks: Receiver<AudioBuffer>,
+) -> Result<(), String> {
+    // the synth loop sends the whole clip at once
+    let Ok(clip) = chunks.recv() else {
+        return Ok(());
+    };
+    let output = audio::output()?;
+    output.play_on(Bus::Soundboard, &clip);
+    let end = Instant::now() + clip.duration();
+    while Instant::now() < end {
+        if kill_flag.load(Ordering::SeqCst) || !output.is_current() {
+            break;
+        }
+        if PLAYBACK.take_skip() {
+            println!("Skipping current sound");
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+/// Plays chunks as they arrive until the sender is dropped and the sink runs dry,
+/// or until the message is skipped. Returns all of the audio that was received.
+/// The chime, if any, plays first and isn't part of the returned audio.
+fn play_chunks(
+    kill_flag: &Arc<AtomicBool>,
+    chunks: Receiver<AudioBuffer>,
+    chime: Option<AudioBuffer>,
+) -> Result<Option<AudioBuffer>, String> {
+    println!("Playing audio");
+    let output = audio::output()?;
+    if PLAYBACK.is_paused() {
+        output.pause();
+    }
+    if let Some(chime) = chime {
+        output.play_on(Bus::Tts, &chime);
+    }
+
+    let limits = LENGTH_LIMITS.lock().unwrap().clone();
+    let mut playing_for = Duration::ZERO;
+    let mut last_tick = Instant::now();
+    // the chime plays first, the clock starts with the speech behind it
+    let mut speech_started = false;
+
+    let mut played: Option<AudioBuffer> = None;
+    let mut synth_done = false;
+    loop {
+        while !synth_done {
+            match chunks.try_recv() {
+                Ok(chunk) => {
+                    output.append(&chunk);
+                    match played.as_mut() {
+                        Some(played) => played.extend(&chunk),
+                        None => played = Some(chunk),
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    synth_done = true;
+                    // the gap is queued behind the message so the next one waits for it
+                    let gap = audio::message_gap();
+                    if let Some(played) = played.as_ref().filter(|_| !gap.is_zero()) {
+                        output.append_silence(gap, played.sample_rate, played.channels);
+                    }
+                }
+            }
+        }
+        if synth_done && output.empty() {
+            break;
+        }
+
+        if kill_flag.load(Ordering::SeqCst) {
+            output.stop();
+            println!("Kill signal received, stopping audio loop...");
+            break;
+        }
+        if !output.is_current() {
+            println!("Output device changed, stopping current message");
+            break;
+        }
+        if PLAYBACK.take_skip() {
+            output.stop();
+            println!("Skipping current message");
+            break;
+        }
+        if PLAYBACK.is_paused() != output.is_paused() {
+            if PLAYBACK.is_paused() {
+                output.pause();
+            } else {
+                output.play();
+            }
+        }
+
+        // only time spent actually playing counts towards the maximum duration
+        let now = Instant::now();
+        speech_started |= output.speaking();
+        if speech_started && !output.is_paused() {
+            playing_for += now - last_tick;
+        }
+        last_tick = now;
+        if let Some(max_duration) = limits.max_duration() {
+            if playing_for >= max_duration {
+                let fade_out = limits.fade_out().as_secs_f32().max(0.001);
+                let faded = (playing_for - max_duration).as_secs_f32() / fade_out;
+                if faded >= 1.0 {
+                    output.stop();
+                    println!("Message reached the maximum duration, stopping");
+                    break;
+                }
+                output.set_fade(1.0 - faded);
+            }
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    output.set_fade(1.0);
+    Ok(played)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::ItemState;
+
+    fn item(kind: MessageKind, text: &str, notice: Option<&str>) -> QueueItem {
+        QueueItem {
+            id: "id".to_string(),
+            user: "foo".to_string(),
+            text: text.to_string(),
+            kind,
+            priority: 0,
+            repeats: 0,
+            bits: 0,
+            reward_id: None,
+            notice: notice.map(str::to_string),
+            enqueued_at: 0,
+            state: ItemState::Waiting,
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn speeds_up_with_the_queue_length() {
+        let rate = AdaptiveRateConfig::default();
+        let fresh = Duration::ZERO;
+        assert_close(rate.length_scale(0, fresh), 1.0);
+        assert_close(rate.length_scale(3, fresh), 1.0);
+        // halfway between 3 and 15 waiting messages
+        assert_close(rate.length_scale(9, fresh), 0.8);
+        assert_close(rate.length_scale(15, fresh), 0.6);
+        // never faster than the minimum
+        assert_close(rate.length_scale(100, fresh), 0.6);
+    }
+
+    #[test]
+    fn speeds_up_with_the_oldest_age() {
+        let rate = AdaptiveRateConfig::default();
+        assert_close(rate.length_scale(0, Duration::from_secs(20)), 1.0);
+        assert_close(rate.length_scale(0, Duration::from_secs(55)), 0.8);
+        assert_close(rate.length_scale(0, Duration::from_secs(600)), 0.6);
+        // the more pressing of the two wins
+        assert_close(rate.length_scale(15, Duration::from_secs(55)), 0.6);
+    }
+
+    #[test]
+    fn adaptive_rate_edge_cases() {
+        let disabled = AdaptiveRateConfig {
+            enabled: false,
+            ..AdaptiveRateConfig::default()
+        };
+        assert_close(disabled.length_scale(100, Duration::from_secs(600)), 1.0);
+
+        // start and full at the same point switch straight to the fastest rate
+        let step = AdaptiveRateConfig {
+            start_queue_length: 5,
+            full_queue_length: 5,
+            ..AdaptiveRateConfig::default()
+        };
+        assert_close(step.length_scale(4, Duration::ZERO), 1.0);
+        assert_close(step.length_scale(5, Duration::ZERO), 0.6);
+    }
+
+    #[test]
+    fn truncates_at_a_word_boundary() {
+        assert_eq!(truncate_text("short", 10), "short");
+        assert_eq!(truncate_text("no limit at all", 0), "no limit at all");
+        assert_eq!(
+            truncate_text("hello there, general kenobi", 16),
+            "hello there. message truncated"
+        );
+        // a single long word is cut mid word
+        assert_eq!(truncate_text("aaaaaaaaaa", 4), "aaaa. message truncated");
+        // counted in characters, not bytes
+        assert_eq!(truncate_text("ééé ééé", 5), "ééé. message truncated");
+    }
+
+    #[test]
+    fn truncates_chat_messages() {
+        let chat = item(MessageKind::Chat, "one two three", None);
+        assert_eq!(
+            spoken_text(&chat, 8),
+            "user foo said one two. message truncated"
+        );
+    }
+
+    #[test]
+    fn truncates_only_the_users_part_of_a_sub() {
+        let notice = "foo subscribed for 12 months!";
+        let sub = item(
+            MessageKind::Subscription,
+            &format!("{} one two three", notice),
+            Some(notice),
+        );
+        assert_eq!(
+            spoken_text(&sub, 8),
+            "foo subscribed for 12 months! one two. message truncated"
+        );
+        let quiet = item(MessageKind::Subscription, notice, Some(notice));
+        assert_eq!(spoken_text(&quiet, 8), notice);
+    }
+}